@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE source_pages;
+
+ALTER TABLE refreshes DROP COLUMN sources_unchanged;
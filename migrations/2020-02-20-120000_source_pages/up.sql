@@ -0,0 +1,10 @@
+-- Validators from the last fetch of each source page, for conditional re-fetching
+CREATE TABLE source_pages (
+    url TEXT PRIMARY KEY NOT NULL,
+    etag TEXT,
+    last_modified TEXT,
+    content_hash TEXT NOT NULL,
+    fetched_dt TEXT NOT NULL
+);
+
+ALTER TABLE refreshes ADD COLUMN sources_unchanged INTEGER NOT NULL DEFAULT 0;
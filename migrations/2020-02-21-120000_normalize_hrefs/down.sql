@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+-- The original malformed links can't be recovered, and the fixed ones still work
@@ -0,0 +1,14 @@
+-- Links used to be glued onto the site's base URL, so absolute hrefs were prefixed
+-- with it and root-relative ones left a double slash
+UPDATE events SET href = substr(href, length('http://www.co-berlin.org/') + 1)
+    WHERE href LIKE 'http://www.co-berlin.org/http%';
+UPDATE events SET href = substr(href, length('http://berghain.de/') + 1)
+    WHERE href LIKE 'http://berghain.de/http%';
+UPDATE events SET href = 'http://www.co-berlin.org/' || ltrim(substr(href, length('http://www.co-berlin.org/') + 1), '/')
+    WHERE href LIKE 'http://www.co-berlin.org//%';
+UPDATE events SET href = 'http://berghain.de/' || ltrim(substr(href, length('http://berghain.de/') + 1), '/')
+    WHERE href LIKE 'http://berghain.de//%';
+
+-- Both sites are served over HTTPS
+UPDATE events SET href = 'https://' || substr(href, length('http://') + 1)
+    WHERE href LIKE 'http://www.co-berlin.org/%' OR href LIKE 'http://berghain.de/%';
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE events DROP COLUMN venue;
+
+DROP TABLE venues;
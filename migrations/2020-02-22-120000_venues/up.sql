@@ -0,0 +1,19 @@
+-- Where events happen, linked from each event by name
+CREATE TABLE venues (
+    name TEXT PRIMARY KEY NOT NULL,
+    address TEXT,
+    district TEXT,
+    latitude DOUBLE,
+    longitude DOUBLE,
+    website TEXT
+);
+
+INSERT INTO venues (name, address, district, latitude, longitude, website) VALUES
+    ('C/O Berlin', 'Hardenbergstraße 22-24, 10623 Berlin', 'Charlottenburg', 52.5068, 13.3327, 'https://www.co-berlin.org'),
+    ('Berghain', 'Am Wriezener Bahnhof, 10243 Berlin', 'Friedrichshain', 52.5111, 13.4430, 'https://www.berghain.berlin'),
+    ('Panorama Bar', 'Am Wriezener Bahnhof, 10243 Berlin', 'Friedrichshain', 52.5111, 13.4430, 'https://www.berghain.berlin');
+
+ALTER TABLE events ADD COLUMN venue TEXT REFERENCES venues (name);
+
+UPDATE events SET venue = 'C/O Berlin' WHERE source = 'CoBerlin';
+UPDATE events SET venue = 'Berghain' WHERE source = 'Berghain';
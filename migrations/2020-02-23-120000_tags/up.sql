@@ -0,0 +1,19 @@
+-- Categories, many-to-many with events
+CREATE TABLE tags (
+    name TEXT PRIMARY KEY NOT NULL
+);
+
+-- `manual` rows were set through the admin API and survive re-tagging, and `removed`
+-- ones hide a tag the rules would otherwise add
+CREATE TABLE event_tags (
+    event_id INTEGER NOT NULL REFERENCES events (id) ON DELETE CASCADE,
+    tag TEXT NOT NULL REFERENCES tags (name),
+    manual BOOLEAN NOT NULL DEFAULT 0,
+    removed BOOLEAN NOT NULL DEFAULT 0,
+    PRIMARY KEY (event_id, tag)
+);
+
+-- The built-in sources' default categories
+INSERT INTO tags (name) VALUES ('photography'), ('club night');
+INSERT INTO event_tags (event_id, tag) SELECT id, 'photography' FROM events WHERE source = 'CoBerlin';
+INSERT INTO event_tags (event_id, tag) SELECT id, 'club night' FROM events WHERE source = 'Berghain';
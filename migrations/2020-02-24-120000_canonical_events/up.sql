@@ -0,0 +1,2 @@
+-- Set on an event that duplicates another source's listing, pointing at the one shown
+ALTER TABLE events ADD COLUMN canonical_id INTEGER REFERENCES events (id);
@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE event_revisions;
+
+ALTER TABLE events DROP COLUMN last_updated;
+ALTER TABLE events DROP COLUMN first_seen;
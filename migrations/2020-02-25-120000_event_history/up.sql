@@ -0,0 +1,14 @@
+-- When each event was first scraped and last changed, as RFC 3339 timestamps
+-- Unknown for events stored before these were tracked
+ALTER TABLE events ADD COLUMN first_seen TEXT;
+ALTER TABLE events ADD COLUMN last_updated TEXT;
+
+-- One row per field a scrape changed
+CREATE TABLE event_revisions (
+    id INTEGER PRIMARY KEY ASC NOT NULL,
+    event_id INTEGER NOT NULL REFERENCES events (id) ON DELETE CASCADE,
+    revised_dt TEXT NOT NULL,
+    field TEXT NOT NULL,
+    old_value TEXT,
+    new_value TEXT
+);
@@ -0,0 +1,7 @@
+-- A single counter bumped by every command that writes events, so a running server
+-- can tell its cached pages are stale with one lookup
+CREATE TABLE data_version (
+    id INTEGER PRIMARY KEY NOT NULL CHECK (id = 1),
+    version INTEGER NOT NULL
+);
+INSERT INTO data_version (id, version) VALUES (1, 0);
@@ -0,0 +1,137 @@
+// assets.rs
+// Static assets, compressed once at startup and served with content-hash ETags
+
+use super::*;
+use hyper::{body::Bytes, header, Body, Request, Response, StatusCode};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+
+/// Cache-Control for fingerprinted URLs - the content behind them never changes
+const IMMUTABLE_CACHE: &str = "public, max-age=31536000, immutable";
+/// Cache-Control for plain URLs - clients must revalidate with the ETag
+const REVALIDATE_CACHE: &str = "no-cache";
+
+lazy_static! {
+    /// Every static asset, keyed by file name
+    pub static ref ASSETS: HashMap<&'static str, StaticAsset> = [
+        StaticAsset::new("main.css", "text/css", include_str!("assets/main.css")),
+        StaticAsset::new("app.js", "application/javascript", include_str!("assets/app.js")),
+        StaticAsset::new(
+            "manifest.json",
+            "application/manifest+json",
+            include_str!("assets/manifest.json"),
+        ),
+        StaticAsset::new("robots.txt", "text/plain", include_str!("assets/robots.txt")),
+    ]
+    .iter()
+    .map(|asset| {
+        let asset = asset.as_ref().expect("Should compress static asset");
+        (asset.name, asset.clone())
+    })
+    .collect();
+}
+
+/// A static asset held in memory in its compressed form
+#[derive(Debug, Clone)]
+pub struct StaticAsset {
+    pub name: &'static str,
+    pub content_type: &'static str,
+    /// DEFLATE compressed body
+    pub compressed: Bytes,
+    /// Start of the hex SHA-256 of the uncompressed body, enough to tell versions apart
+    pub hash: String,
+}
+
+impl StaticAsset {
+    /// Hash and compress an asset
+    fn new(name: &'static str, content_type: &'static str, body: &str) -> AppResult<Self> {
+        let mut hash = content_hash(body.as_bytes());
+        hash.truncate(16);
+        Ok(Self {
+            name,
+            content_type,
+            compressed: Bytes::from(deflate(body.as_bytes())?),
+            hash,
+        })
+    }
+    /// Strong ETag for this asset
+    pub fn etag(&self) -> String {
+        format!("\"{}\"", self.hash)
+    }
+    /// URL with the content hash spliced in before the extension, e.g. `/main.0123abcd.css`
+    pub fn fingerprinted_path(&self) -> String {
+        match self.name.rsplit_once('.') {
+            Some((stem, ext)) => format!("/{}.{}.{}", stem, self.hash, ext),
+            None => format!("/{}.{}", self.name, self.hash),
+        }
+    }
+}
+
+/// Fingerprinted URL for the named asset, for use in templates
+pub fn asset_path(name: &str) -> String {
+    match ASSETS.get(name) {
+        Some(asset) => asset.fingerprinted_path(),
+        None => format!("/{}", name),
+    }
+}
+
+/// Find the asset a request path refers to
+/// Returns the asset and whether the path carried its current fingerprint.
+/// A stale fingerprint still resolves, but is served as a plain URL so it gets revalidated.
+pub fn lookup_asset(path: &str) -> Option<(&'static StaticAsset, bool)> {
+    let file_name = path.strip_prefix('/')?;
+    if let Some(asset) = ASSETS.get(file_name) {
+        return Some((asset, false));
+    }
+    let (rest, ext) = file_name.rsplit_once('.')?;
+    let (stem, hash) = rest.rsplit_once('.')?;
+    let asset = ASSETS.get(format!("{}.{}", stem, ext).as_str())?;
+    Some((asset, asset.hash == hash))
+}
+
+/// Serve a static asset, or 304 if the client already has it
+pub async fn asset_handler(
+    req: &Request<Body>,
+    asset: &StaticAsset,
+    fingerprinted: bool,
+) -> HandlerResult {
+    let etag = asset.etag();
+    let cache_control = if fingerprinted {
+        IMMUTABLE_CACHE
+    } else {
+        REVALIDATE_CACHE
+    };
+    if etag_matches(req.headers(), &etag) {
+        return not_modified(&etag, cache_control);
+    }
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, asset.content_type)
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, cache_control);
+    deflated_response(response, req.headers(), asset.compressed.clone())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_lookup_asset() {
+        let css = &ASSETS["main.css"];
+        let fingerprinted = css.fingerprinted_path();
+        assert!(!lookup_asset("/main.css").unwrap().1);
+        assert!(lookup_asset(&fingerprinted).unwrap().1);
+        assert!(!lookup_asset("/main.0000000000000000.css").unwrap().1);
+        assert!(lookup_asset("/missing.css").is_none());
+        assert_eq!(asset_path("app.js"), ASSETS["app.js"].fingerprinted_path());
+    }
+
+    #[test]
+    fn test_stable_fingerprint() {
+        // The start of SHA-256("abc"), so fingerprints survive toolchain upgrades
+        let asset = StaticAsset::new("x.css", "text/css", "abc").unwrap();
+        assert_eq!(asset.fingerprinted_path(), "/x.ba7816bf8f01cfea.css");
+    }
+}
@@ -0,0 +1,241 @@
+// cache.rs
+// In-memory cache of rendered event listings
+
+use super::*;
+use chrono::{NaiveDate, Utc};
+use hyper::body::Bytes;
+use log::debug;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, RwLock,
+    },
+};
+
+/// Past this many distinct filters the cache is emptied rather than grown
+const MAX_ENTRIES: usize = 256;
+
+/// Normalized set of filters for the event listing, used as the cache key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct ListingQuery {
+    /// Explicit start date, or None for the earliest stored event
+    pub begin_date: Option<String>,
+    /// Explicit end date, or None for the latest stored event
+    pub end_date: Option<String>,
+    pub sources: Vec<EventSource>,
+    /// Title search, "%" matching anything
+    pub title_like: String,
+    /// Venue names to include, or empty for all of them
+    pub venues: Vec<String>,
+    /// Districts to include, or empty for all of them
+    pub districts: Vec<String>,
+    /// Include only events with one of these tags, or any if empty
+    pub categories: Vec<String>,
+    /// Also list events that duplicate another source's listing
+    pub duplicates: bool,
+}
+
+impl ListingQuery {
+    /// Build a query from submitted form parameters, offering every known source
+    pub fn from_params(
+        params: &HashMap<String, String>,
+        registry: &SourceRegistry,
+    ) -> AppResult<Self> {
+        let mut sources = registry
+            .all()
+            .into_iter()
+            .map(|source| {
+                if params.contains_key(&source.markup_name()) {
+                    source
+                } else {
+                    source.toggle()
+                }
+            })
+            .collect::<Vec<EventSource>>();
+
+        // If none were checked, include everything
+        if !sources.iter().any(|s| s.enabled()) {
+            sources = registry.all()
+        }
+
+        let non_empty = |name: &str| {
+            params
+                .get(name)
+                .map(|s| s.trim())
+                .filter(|s| !s.is_empty())
+                .map(String::from)
+        };
+
+        let date = |name: &str| match non_empty(name) {
+            Some(s) => match NaiveDate::parse_from_str(&s, "%F") {
+                Ok(_) => Ok(Some(s)),
+                Err(_) => Err(AppError::Validation(format!(
+                    "{} must be a YYYY-MM-DD date, got {:?}",
+                    name, s
+                ))),
+            },
+            None => Ok(None),
+        };
+
+        Ok(Self {
+            begin_date: date("startdate")?,
+            end_date: date("enddate")?,
+            sources,
+            title_like: non_empty("title").unwrap_or_else(|| "%".into()),
+            venues: non_empty("venue").into_iter().collect(),
+            districts: non_empty("district").into_iter().collect(),
+            categories: non_empty("category").into_iter().collect(),
+            duplicates: false,
+        })
+    }
+}
+
+/// A rendered listing page and its validators
+#[derive(Debug, Clone)]
+pub struct CachedPage {
+    /// DEFLATE compressed HTML
+    pub body: Bytes,
+    /// Hash of the rendered HTML
+    pub etag: String,
+    /// HTTP-date of when this filter's page first rendered as `etag`
+    pub last_modified: String,
+}
+
+/// Rendered listings keyed by filter, dropped whenever the events change
+#[derive(Debug, Default)]
+pub struct ListingCache {
+    entries: RwLock<HashMap<ListingQuery, CachedPage>>,
+    /// Bumped on every invalidation so renders racing a write aren't stored
+    generation: AtomicU64,
+    /// Data version the entries were rendered from
+    version: Mutex<Option<i32>>,
+    /// Each filter's latest ETag and when it first appeared, kept across invalidations
+    changes: Mutex<HashMap<ListingQuery, (String, String)>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ListingCache {
+    /// Look up a rendered page, counting the hit or miss
+    pub fn get(&self, query: &ListingQuery) -> Option<CachedPage> {
+        let found = self.entries.read().unwrap().get(query).cloned();
+        if found.is_some() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        let (hits, misses) = self.stats();
+        debug!("Listing cache: {} hits, {} misses", hits, misses);
+        found
+    }
+    /// Drop every cached page if another process bumped the data version since the last call
+    /// Catches writes from the `scrape`, `import` and `prune` subcommands.
+    pub fn sync(&self, version: i32) {
+        let mut current = self.version.lock().unwrap();
+        if current.is_some() && *current != Some(version) {
+            self.invalidate();
+        }
+        *current = Some(version);
+    }
+    /// Current generation, to be passed back to `insert`
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+    /// Store a page rendered during `generation`, unless the cache was invalidated since
+    pub fn insert(&self, generation: u64, query: ListingQuery, page: CachedPage) {
+        let mut entries = self.entries.write().unwrap();
+        if generation != self.generation() {
+            return;
+        }
+        if entries.len() >= MAX_ENTRIES {
+            entries.clear();
+        }
+        entries.insert(query, page);
+    }
+    /// HTTP-date of when the page for `query` first rendered as `etag`
+    /// Pages that come out the same after an invalidation keep their old date.
+    pub fn last_modified(&self, query: &ListingQuery, etag: &str) -> String {
+        let mut changes = self.changes.lock().unwrap();
+        match changes.get(query) {
+            Some((seen, since)) if seen == etag => since.clone(),
+            _ => {
+                if changes.len() >= MAX_ENTRIES {
+                    changes.clear();
+                }
+                let since = format_http_date(Utc::now());
+                changes.insert(query.clone(), (etag.into(), since.clone()));
+                since
+            }
+        }
+    }
+    /// Drop every cached page
+    pub fn invalidate(&self) {
+        let mut entries = self.entries.write().unwrap();
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        entries.clear();
+    }
+    /// Total (hits, misses) since startup
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn page() -> CachedPage {
+        CachedPage {
+            body: Bytes::from_static(b""),
+            etag: "\"0\"".into(),
+            last_modified: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let cache = ListingCache::default();
+        let registry = SourceRegistry::new(&Opt::defaults().unwrap());
+        let query = ListingQuery::from_params(&HashMap::new(), &registry).unwrap();
+        assert!(cache.get(&query).is_none());
+
+        let generation = cache.generation();
+        cache.insert(generation, query.clone(), page());
+        assert!(cache.get(&query).is_some());
+
+        cache.invalidate();
+        assert!(cache.get(&query).is_none());
+
+        // A render that started before the invalidation must not be stored
+        cache.insert(generation, query.clone(), page());
+        assert!(cache.get(&query).is_none());
+        assert_eq!(cache.stats(), (1, 3));
+    }
+
+    #[test]
+    fn test_last_modified() {
+        let cache = ListingCache::default();
+        let registry = SourceRegistry::new(&Opt::defaults().unwrap());
+        let query = ListingQuery::from_params(&HashMap::new(), &registry).unwrap();
+        let first = cache.last_modified(&query, "\"a\"");
+        cache.invalidate();
+        assert_eq!(cache.last_modified(&query, "\"a\""), first);
+        cache.changes.lock().unwrap().get_mut(&query).unwrap().1 = "earlier".into();
+        assert_ne!(cache.last_modified(&query, "\"b\""), "earlier");
+    }
+
+    #[test]
+    fn test_invalid_date() {
+        let registry = SourceRegistry::new(&Opt::defaults().unwrap());
+        let mut params = HashMap::new();
+        params.insert("startdate".to_string(), "next tuesday".to_string());
+        assert!(ListingQuery::from_params(&params, &registry).is_err());
+        params.insert("startdate".to_string(), "2020-02-17".to_string());
+        assert!(ListingQuery::from_params(&params, &registry).is_ok());
+    }
+}
@@ -0,0 +1,477 @@
+// commands.rs
+// Subcommands, for running the server or maintenance from the shell
+
+use super::*;
+use chrono::NaiveDate;
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Server,
+};
+use log::{error, info};
+use std::{
+    convert::{Infallible, TryInto},
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::PathBuf,
+    sync::Arc,
+};
+use structopt::StructOpt;
+
+/// What to do once configured
+#[derive(Debug, StructOpt)]
+pub enum Command {
+    /// Run the web server (the default)
+    Serve,
+    /// Scrape event sources into the database
+    Scrape {
+        /// Only scrape this source, even if it's disabled in the config
+        #[structopt(long)]
+        source: Option<String>,
+        /// Scrape every page, even those unchanged since the last scrape
+        #[structopt(long)]
+        force: bool,
+        /// Print what would be inserted, updated or left alone, without writing anything
+        #[structopt(long)]
+        dry_run: bool,
+        /// Parse this saved page instead of fetching the source
+        #[structopt(long, parse(from_os_str), requires_all = &["source", "dry-run"])]
+        file: Option<PathBuf>,
+        /// Print the dry run as JSON
+        #[structopt(long, requires = "dry-run")]
+        json: bool,
+    },
+    /// Apply any pending database migrations
+    Migrate,
+    /// Print stored events
+    ListEvents {
+        #[structopt(flatten)]
+        filter: EventFilter,
+        /// Include events that duplicate another source's listing
+        #[structopt(long)]
+        duplicates: bool,
+    },
+    /// Write stored events to stdout or a file
+    Export {
+        #[structopt(short, long, default_value = "json", possible_values = Format::NAMES)]
+        format: Format,
+        /// File to write instead of stdout
+        #[structopt(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+        #[structopt(flatten)]
+        filter: EventFilter,
+        /// Leave out events that duplicate another source's listing
+        #[structopt(long)]
+        no_duplicates: bool,
+    },
+    /// Load events from a JSON or CSV export, skipping any already stored
+    Import {
+        #[structopt(short, long, default_value = "json", possible_values = &["json", "csv"])]
+        format: Format,
+        /// File to read, or - for stdin
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Delete events that finished before a date
+    Prune {
+        /// YYYY-MM-DD
+        #[structopt(long)]
+        before: NaiveDate,
+    },
+}
+
+/// Filters for commands that read stored events
+#[derive(Debug, StructOpt)]
+pub struct EventFilter {
+    /// Only events from this source - may be repeated
+    #[structopt(long = "source")]
+    sources: Vec<String>,
+    /// Only events whose title contains this
+    #[structopt(long)]
+    title: Option<String>,
+    /// Only events held at this venue - may be repeated
+    #[structopt(long = "venue")]
+    venues: Vec<String>,
+    /// Only events held in this district - may be repeated
+    #[structopt(long = "district")]
+    districts: Vec<String>,
+    /// Only events tagged with this category - may be repeated
+    #[structopt(long = "category")]
+    categories: Vec<String>,
+    /// Only events on or after this date, YYYY-MM-DD
+    #[structopt(long)]
+    from: Option<NaiveDate>,
+    /// Only events on or before this date, YYYY-MM-DD
+    #[structopt(long)]
+    to: Option<NaiveDate>,
+}
+
+impl EventFilter {
+    /// Load the matching events, with or without duplicates of other sources' listings
+    fn events(&self, state: &AppState, duplicates: bool) -> AppResult<Vec<Event>> {
+        let conn = state.pool.get()?;
+        let mut sources = state.sources.all();
+        if !self.sources.is_empty() {
+            let wanted = self
+                .sources
+                .iter()
+                .map(|name| {
+                    state
+                        .sources
+                        .get(name)
+                        .map(|(source, _)| source.clone())
+                        .ok_or_else(|| AppError::Validation(format!("unknown source {:?}", name)))
+                })
+                .collect::<AppResult<Vec<EventSource>>>()?;
+            for source in sources.iter_mut() {
+                if !wanted.contains(source) {
+                    *source = source.toggle();
+                }
+            }
+        }
+        let (mut begin_date, mut end_date) = total_event_range(&conn)?;
+        if let Some(from) = self.from {
+            begin_date = from.to_string();
+        }
+        if let Some(to) = self.to {
+            end_date = to.to_string();
+        }
+        let query = ListingQuery {
+            begin_date: None,
+            end_date: None,
+            sources,
+            title_like: self.title.clone().unwrap_or_else(|| "%".into()),
+            venues: self.venues.clone(),
+            districts: self.districts.clone(),
+            categories: self.categories.clone(),
+            duplicates,
+        };
+        filtered_events(&begin_date, &end_date, &query, &conn)
+    }
+}
+
+/// Run a command to completion
+pub async fn run(opt: Opt, command: Command) -> AppResult<()> {
+    use Command::*;
+    match command {
+        Serve => serve(open_state(opt)?).await,
+        Scrape {
+            source,
+            dry_run: false,
+            force,
+            ..
+        } => scrape(&*open_state(opt)?, source.as_deref(), force).await,
+        Scrape {
+            source, file, json, ..
+        } => dry_run(&*open_state_as_is(opt)?, source.as_deref(), file, json).await,
+        Migrate => migrate(&opt),
+        ListEvents { filter, duplicates } => list_events(&*open_state(opt)?, &filter, duplicates),
+        Export {
+            format,
+            output,
+            filter,
+            no_duplicates,
+        } => export(&*open_state(opt)?, format, output, &filter, !no_duplicates),
+        Import { format, file } => import(&*open_state(opt)?, format, &file),
+        Prune { before } => prune(&*open_state(opt)?, before),
+    }
+}
+
+/// Open the database, bringing it up to date, and build the shared state around it
+fn open_state(opt: Opt) -> AppResult<SharedState> {
+    let pool = establish_and_run_migrations(&opt.database_url, opt.database_pool_size)
+        .inspect_err(|_| {
+            error!("Could not open database {}", opt.database_url);
+        })?;
+    Ok(Arc::new(AppState::new(opt, pool)?))
+}
+
+/// Open the database without migrating it, for commands that mustn't write to it
+/// Fails if a migration is pending, since the queries may not match the schema.
+fn open_state_as_is(opt: Opt) -> AppResult<SharedState> {
+    let pool = establish_pool(&opt.database_url, opt.database_pool_size)?;
+    if !migrations_current(&*pool.get()?)? {
+        return Err(AppError::Validation(format!(
+            "database {} has pending migrations - run `migrate` first",
+            opt.database_url
+        )));
+    }
+    Ok(Arc::new(AppState::new(opt, pool)?))
+}
+
+/// Serve the site until the process is stopped
+async fn serve(state: SharedState) -> AppResult<()> {
+    let addr = format!("{}:{}", state.opt.address, state.opt.port)
+        .parse()
+        .map_err(|e| AppError::Validation(format!("bad server address: {}", e)))?;
+
+    let make_svc = make_service_fn(move |_conn| {
+        let state = state.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(req, state.clone()))) }
+    });
+
+    let server = Server::bind(&addr).serve(make_svc);
+
+    info!("Serving {} on {}", env!("CARGO_PKG_NAME"), addr);
+
+    Ok(server.await?)
+}
+
+/// The named source, or every enabled one
+fn chosen_sources<'a>(
+    state: &'a AppState,
+    source: Option<&str>,
+) -> AppResult<Vec<(&'a EventSource, &'a SourceConfig)>> {
+    match source {
+        Some(name) => Ok(vec![state.sources.get(name).ok_or_else(|| {
+            AppError::Validation(format!("unknown source {:?}", name))
+        })?]),
+        None => Ok(state.sources.enabled().collect()),
+    }
+}
+
+/// Scrape one or all enabled sources and record a refresh
+async fn scrape(state: &AppState, source: Option<&str>, force: bool) -> AppResult<()> {
+    let sources = chosen_sources(state, source)?;
+    let totals = EventSource::scrape_sources(state, sources.into_iter(), force).await?;
+    let conn = state.pool.get()?;
+    create_refresh(
+        &conn,
+        totals.added.try_into().unwrap(),
+        totals.unchanged.try_into().unwrap(),
+    )?;
+    bump_data_version(&conn)?;
+    println!(
+        "Added {} new events, {} sources unchanged",
+        totals.added, totals.unchanged
+    );
+    Ok(())
+}
+
+/// Print what scraping would change, fetching each source or parsing a saved page
+async fn dry_run(
+    state: &AppState,
+    source: Option<&str>,
+    file: Option<PathBuf>,
+    json: bool,
+) -> AppResult<()> {
+    let mut diffs = Vec::new();
+    let stored = all_events(&*state.pool.get()?)?;
+    for (src, config) in chosen_sources(state, source)? {
+        let page = match &file {
+            Some(path) => src.fetch_page(&Fetcher::local(path), config).await?,
+            None => src.fetch_page(&state.fetcher, config).await?,
+        };
+        let diff = src
+            .diff_events(&page, config, &stored)
+            .map_err(|e| src.tag_error(e))?;
+        diffs.push(diff);
+    }
+
+    if json {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        serde_json::to_writer_pretty(&mut out, &diffs).map_err(|e| AppError::Internal(e.into()))?;
+        writeln!(out)?;
+    } else {
+        for diff in &diffs {
+            print!("{}", diff);
+        }
+    }
+    Ok(())
+}
+
+/// Apply pending migrations, listing each one
+fn migrate(opt: &Opt) -> AppResult<()> {
+    let pool = establish_pool(&opt.database_url, 1)?;
+    run_migrations(&*pool.get()?, &mut io::stdout())?;
+    println!("Database {} is up to date", opt.database_url);
+    Ok(())
+}
+
+/// Print matching events, one per line
+fn list_events(state: &AppState, filter: &EventFilter, duplicates: bool) -> AppResult<()> {
+    let events = filter.events(state, duplicates)?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for event in &events {
+        writeln!(
+            out,
+            "{:>5}  {:<19}  {:<10}  {}",
+            event.id, event.event_date, event.source, event.title
+        )?;
+    }
+    writeln!(out, "{} events", events.len())?;
+    Ok(())
+}
+
+/// Write matching events to a file or stdout
+fn export(
+    state: &AppState,
+    format: Format,
+    output: Option<PathBuf>,
+    filter: &EventFilter,
+    duplicates: bool,
+) -> AppResult<()> {
+    let events = filter.events(state, duplicates)?;
+    match output {
+        Some(path) => {
+            let mut out = BufWriter::new(File::create(&path)?);
+            write_events(&events, format, &mut out)?;
+            out.flush()?;
+            info!("Wrote {} events to {}", events.len(), path.display());
+        }
+        None => write_events(&events, format, &mut io::stdout().lock())?,
+    }
+    Ok(())
+}
+
+/// Insert the events from an export that aren't already stored
+fn import(state: &AppState, format: Format, file: &PathBuf) -> AppResult<()> {
+    let events = if file.to_str() == Some("-") {
+        read_events(format, io::stdin().lock())?
+    } else {
+        read_events(format, BufReader::new(File::open(file)?))?
+    };
+    let conn = state.pool.get()?;
+    let stored = all_events(&conn)?;
+    let total = events.len();
+    let mut added = 0;
+    for event in events {
+        if !stored.iter().any(|s| *s == event) {
+            let tags = state.tagger.tags(&event);
+            added += create_event(&conn, event)?;
+            set_auto_tags(&conn, last_insert_id(&conn)?, &tags)?;
+        }
+    }
+    dedup_events(&conn)?;
+    bump_data_version(&conn)?;
+    println!("Imported {} of {} events", added, total);
+    Ok(())
+}
+
+/// Delete events that finished before a date
+fn prune(state: &AppState, before: NaiveDate) -> AppResult<()> {
+    let conn = state.pool.get()?;
+    let deleted = delete_events_before(&conn, before)?;
+    // Duplicates of a deleted event may need a new canonical one
+    dedup_events(&conn)?;
+    bump_data_version(&conn)?;
+    println!("Deleted {} events that finished before {}", deleted, before);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    /// State around a fresh in-memory database
+    fn state() -> AppState {
+        AppState::new(Opt::defaults().unwrap(), test_pool()).unwrap()
+    }
+
+    fn store(state: &AppState, title: &str, date: &str, source: EventSource) {
+        let event = NewEvent::new(title, None, "#", "Synopsis", date, None, source);
+        create_event(&state.pool.get().unwrap(), event).unwrap();
+    }
+
+    fn titles(state: &AppState) -> Vec<String> {
+        let mut titles = all_events(&state.pool.get().unwrap())
+            .unwrap()
+            .into_iter()
+            .map(|e| e.title)
+            .collect::<Vec<String>>();
+        titles.sort();
+        titles
+    }
+
+    #[test]
+    fn test_export_import_round_trip() {
+        let from = state();
+        store(
+            &from,
+            "Light and Shadow",
+            "2020-03-01",
+            EventSource::CoBerlin(true),
+        );
+        store(
+            &from,
+            "Klubnacht",
+            "2020-03-06 23:59:00",
+            EventSource::Berghain(true),
+        );
+        // Exports keep duplicates, so nothing is lost on the way
+        store(
+            &from,
+            "Klubnacht",
+            "2020-03-06 23:00:00",
+            EventSource::CoBerlin(true),
+        );
+        assert_eq!(dedup_events(&from.pool.get().unwrap()).unwrap(), 1);
+        let dir = tempfile::tempdir().unwrap();
+        for format in &[Format::Json, Format::Csv] {
+            let path = dir.path().join(format!("events.{:?}", format));
+            export(
+                &from,
+                *format,
+                Some(path.clone()),
+                &EventFilter::from_iter(&["export"]),
+                true,
+            )
+            .unwrap();
+
+            let to = state();
+            import(&to, *format, &path).unwrap();
+            assert_eq!(titles(&to), titles(&from));
+            assert_eq!(data_version(&to.pool.get().unwrap()).unwrap(), 1);
+
+            // Importing again skips everything already stored
+            import(&to, *format, &path).unwrap();
+            assert_eq!(titles(&to).len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_open_state_as_is() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut opt = Opt::defaults().unwrap();
+        opt.database_url = dir.path().join("dry.sqlite").to_string_lossy().into();
+        assert!(open_state_as_is(opt.clone()).is_err());
+        // Still unmigrated, so nothing was written
+        let pool = establish_pool(&opt.database_url, 1).unwrap();
+        assert!(!migrations_current(&pool.get().unwrap()).unwrap());
+
+        run_migrations(&pool.get().unwrap(), &mut io::sink()).unwrap();
+        assert!(open_state_as_is(opt).is_ok());
+    }
+
+    #[test]
+    fn test_prune() {
+        let state = state();
+        store(&state, "Opening", "2020-02-10", EventSource::CoBerlin(true));
+        store(
+            &state,
+            "Klubnacht",
+            "2020-02-21 23:59:00",
+            EventSource::Berghain(true),
+        );
+        let event = NewEvent {
+            event_end_date: Some("2020-04-30".into()),
+            ..NewEvent::new(
+                "Light and Shadow",
+                None,
+                "#",
+                "",
+                "2020-02-01",
+                None,
+                EventSource::CoBerlin(true),
+            )
+        };
+        create_event(&state.pool.get().unwrap(), event).unwrap();
+
+        prune(&state, NaiveDate::from_ymd_opt(2020, 2, 20).unwrap()).unwrap();
+        // Still running after the cutoff, so kept
+        assert_eq!(titles(&state), vec!["Klubnacht", "Light and Shadow"]);
+        assert_eq!(data_version(&state.pool.get().unwrap()).unwrap(), 1);
+    }
+}
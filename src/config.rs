@@ -1,38 +1,454 @@
 // config.rs
-// CLI options and logging setup
+// Layered configuration and logging setup
+//
+// Each setting is resolved, lowest precedence first, from:
+// 1. the built-in defaults in `assets/config.toml`
+// 2. an optional TOML file passed with `--config` or `DALIA_CONFIG`
+// 3. `DALIA_*` environment variables
+// 4. command-line flags
 use super::*;
-use lazy_static::lazy_static;
-use log::{info, trace, warn};
+use chrono::Utc;
+use log::{info, trace, warn, Level};
 use serde_derive::Deserialize;
-use std::env::{set_var, var};
+use std::{
+    collections::HashMap,
+    env::{set_var, var},
+    fs,
+    io::Write,
+    path::PathBuf,
+};
 use structopt::StructOpt;
 
-/// deciduously-com backend
-#[derive(Debug, Deserialize, StructOpt)]
-#[structopt(name = "deciduously-com")]
+/// Names accepted for `log_level`, in increasing verbosity
+const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+/// Names accepted for `log_format`
+const LOG_FORMATS: [&str; 2] = ["text", "json"];
+
+/// Fully resolved runtime configuration
+#[derive(Debug, Clone, Deserialize)]
 pub struct Opt {
     /// Server address
-    #[structopt(short, long, default_value = "127.0.0.1")]
     pub address: String,
     /// Server port 0-65535
-    #[structopt(short, long, default_value = "3000")]
     pub port: u16,
+    /// SQLite database path
+    pub database_url: String,
+    /// Maximum open database connections
+    pub database_pool_size: u32,
+    /// One of error, warn, info, debug, trace
+    pub log_level: String,
+    /// Either text, or json for one object per line
+    pub log_format: String,
+    /// Requests taking at least this many milliseconds are logged as warnings
+    pub slow_request_ms: u64,
+    /// Minimum seconds between scrapes of the sources
+    pub refresh_interval: u64,
+    /// Seconds after the last refresh before /readyz reports stale data, unchecked if unset
+    pub max_refresh_age: Option<u64>,
+    /// Directory of static files served under /images/
+    pub static_dir: PathBuf,
+    /// User-Agent sent to the event sources
+    pub user_agent: String,
+    /// Minimum milliseconds between requests to the same host
+    pub fetch_delay_ms: u64,
+    /// Longest Retry-After, in seconds, we'll wait out before giving up on a source
+    pub max_retry_wait: u64,
+    /// Saved page or directory of pages to scrape instead of the network
+    pub pages_from: Option<PathBuf>,
+    /// Directory to save a timestamped copy of every page fetched
+    pub archive_dir: Option<PathBuf>,
+    /// Bearer token for the admin API, which is off without one
+    pub admin_token: Option<String>,
+    /// Per-source settings, keyed by `EventSource::as_str()`
+    pub sources: HashMap<String, SourceConfig>,
+    /// Keywords that tag an event when found in its title, subtitle or synopsis, by tag
+    pub tag_rules: HashMap<String, Vec<String>>,
+}
+
+/// Settings for a single event source
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct SourceConfig {
+    /// Whether refreshes scrape this source
+    pub enabled: bool,
+    /// How the source's page is parsed
+    #[serde(default)]
+    pub kind: SourceKind,
+    /// Override for the calendar page URL, required for sources added in the config
+    pub calendar_url: Option<String>,
+    /// Feed fields to read each event column from, for iCal and RSS sources
+    #[serde(default)]
+    pub fields: FieldMap,
+    /// Venue for events the parser can't place, overriding any built-in default
+    pub venue: Option<String>,
+    /// Tags for every event from this source, overriding any built-in default
+    pub categories: Option<Vec<String>>,
+}
+
+/// How a source's page is parsed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceKind {
+    /// One of the hand-written parsers, chosen by the source's name
+    #[default]
+    Builtin,
+    /// Any page embedding schema.org Events as JSON-LD
+    JsonLd,
+    /// An iCalendar feed, one event per VEVENT
+    Ical,
+    /// An RSS or Atom feed, one event per item or entry
+    Rss,
 }
 
-lazy_static! {
-    pub static ref OPT: Opt = {
-        let toml_opt = include_str!("assets/config.toml");
-        if std::env::args().nth(2).is_some() {
-            // If anything was passed, override config.toml
-            Opt::from_args()
-        } else {
-            toml::from_str(toml_opt).unwrap()
+impl SourceKind {
+    /// Name as written in the config
+    pub fn as_str(self) -> &'static str {
+        use SourceKind::*;
+        match self {
+            Builtin => "builtin",
+            JsonLd => "json-ld",
+            Ical => "ical",
+            Rss => "rss",
         }
-    };
+    }
+}
+
+/// Which feed field fills each event column
+/// Names are iCal properties such as `X-SUBTITLE`, or item elements such as `ev:startdate`,
+/// whose namespace prefix is ignored. Anything unset uses the format's usual field.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FieldMap {
+    pub title: Option<String>,
+    pub subtitle: Option<String>,
+    pub synopsis: Option<String>,
+    pub href: Option<String>,
+    pub start: Option<String>,
+    pub end: Option<String>,
+}
+
+impl FieldMap {
+    /// Whether any field is remapped
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+    /// Override with every mapping present in a higher layer
+    fn apply(&mut self, layer: FieldMap) {
+        let fields = [
+            (&mut self.title, layer.title),
+            (&mut self.subtitle, layer.subtitle),
+            (&mut self.synopsis, layer.synopsis),
+            (&mut self.href, layer.href),
+            (&mut self.start, layer.start),
+            (&mut self.end, layer.end),
+        ];
+        for (field, mapped) in fields {
+            if mapped.is_some() {
+                *field = mapped;
+            }
+        }
+    }
+}
+
+/// Source settings as given in a config file, where every key is optional
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SourceLayer {
+    enabled: Option<bool>,
+    kind: Option<SourceKind>,
+    calendar_url: Option<String>,
+    fields: Option<FieldMap>,
+    venue: Option<String>,
+    categories: Option<Vec<String>>,
+}
+
+/// Command line: configuration flags, then what to do
+#[derive(Debug, StructOpt)]
+#[structopt(name = "dalia-challenge", about = "Berlin cultural events aggregator")]
+pub struct Cli {
+    #[structopt(flatten)]
+    pub layer: OptLayer,
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// One layer of configuration - anything left unset falls through to the layer below
+/// Parsed from a config file, or from the environment and command line together.
+#[derive(Debug, Default, Deserialize, StructOpt)]
+#[serde(deny_unknown_fields)]
+pub struct OptLayer {
+    /// TOML config file layered over the built-in defaults
+    #[structopt(short, long, env = "DALIA_CONFIG", parse(from_os_str))]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Server address
+    #[structopt(short, long, env = "DALIA_ADDRESS")]
+    address: Option<String>,
+    /// Server port 0-65535
+    #[structopt(short, long, env = "DALIA_PORT")]
+    port: Option<u16>,
+    /// SQLite database path
+    #[structopt(short, long, env = "DALIA_DATABASE_URL")]
+    database_url: Option<String>,
+    /// Maximum open database connections
+    #[structopt(long, env = "DALIA_DATABASE_POOL_SIZE")]
+    database_pool_size: Option<u32>,
+    /// One of error, warn, info, debug, trace
+    #[structopt(short, long, env = "DALIA_LOG_LEVEL")]
+    log_level: Option<String>,
+    /// Either text, or json for one object per line
+    #[structopt(long, env = "DALIA_LOG_FORMAT")]
+    log_format: Option<String>,
+    /// Requests taking at least this many milliseconds are logged as warnings
+    #[structopt(long, env = "DALIA_SLOW_REQUEST_MS")]
+    slow_request_ms: Option<u64>,
+    /// Minimum seconds between scrapes of the sources
+    #[structopt(short, long, env = "DALIA_REFRESH_INTERVAL")]
+    refresh_interval: Option<u64>,
+    /// Seconds after the last refresh before /readyz reports stale data, unchecked if unset
+    #[structopt(long, env = "DALIA_MAX_REFRESH_AGE")]
+    max_refresh_age: Option<u64>,
+    /// Directory of static files served under /images/
+    #[structopt(short, long, env = "DALIA_STATIC_DIR", parse(from_os_str))]
+    static_dir: Option<PathBuf>,
+    /// User-Agent sent to the event sources
+    #[structopt(long, env = "DALIA_USER_AGENT")]
+    user_agent: Option<String>,
+    /// Minimum milliseconds between requests to the same host
+    #[structopt(long, env = "DALIA_FETCH_DELAY_MS")]
+    fetch_delay_ms: Option<u64>,
+    /// Longest Retry-After, in seconds, we'll wait out before giving up on a source
+    #[structopt(long, env = "DALIA_MAX_RETRY_WAIT")]
+    max_retry_wait: Option<u64>,
+    /// Saved page or directory of pages to scrape instead of the network
+    #[structopt(long, env = "DALIA_PAGES_FROM", parse(from_os_str))]
+    pages_from: Option<PathBuf>,
+    /// Directory to save a timestamped copy of every page fetched
+    #[structopt(long, env = "DALIA_ARCHIVE_DIR", parse(from_os_str))]
+    archive_dir: Option<PathBuf>,
+    /// Bearer token for the admin API, which is off without one
+    #[structopt(long, env = "DALIA_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<String>,
+    /// Comma-separated sources to scrape, disabling all others
+    #[structopt(long = "sources", env = "DALIA_SOURCES", require_delimiter = true)]
+    #[serde(skip)]
+    enabled_sources: Option<Vec<String>>,
+    #[structopt(skip)]
+    sources: HashMap<String, SourceLayer>,
+    #[structopt(skip)]
+    #[serde(default)]
+    tag_rules: HashMap<String, Vec<String>>,
+}
+
+impl Opt {
+    /// Built-in defaults alone
+    pub fn defaults() -> AppResult<Self> {
+        toml::from_str(include_str!("assets/config.toml")).map_err(|e| AppError::Internal(e.into()))
+    }
+    /// Resolve the defaults and config file beneath an already-parsed env/CLI layer
+    pub fn from_layers(cli: OptLayer) -> AppResult<Self> {
+        let mut opt = Self::defaults()?;
+        if let Some(path) = &cli.config {
+            let contents = fs::read_to_string(path)?;
+            let file: OptLayer = toml::from_str(&contents)
+                .map_err(|e| AppError::Validation(format!("{}: {}", path.to_string_lossy(), e)))?;
+            opt.apply(file);
+        }
+        opt.apply(cli);
+        opt.validate()?;
+        Ok(opt)
+    }
+    /// Override with every setting present in a higher layer
+    fn apply(&mut self, layer: OptLayer) {
+        if let Some(address) = layer.address {
+            self.address = address;
+        }
+        if let Some(port) = layer.port {
+            self.port = port;
+        }
+        if let Some(database_url) = layer.database_url {
+            self.database_url = database_url;
+        }
+        if let Some(database_pool_size) = layer.database_pool_size {
+            self.database_pool_size = database_pool_size;
+        }
+        if let Some(log_level) = layer.log_level {
+            self.log_level = log_level.to_lowercase();
+        }
+        if let Some(log_format) = layer.log_format {
+            self.log_format = log_format.to_lowercase();
+        }
+        if let Some(slow_request_ms) = layer.slow_request_ms {
+            self.slow_request_ms = slow_request_ms;
+        }
+        if let Some(refresh_interval) = layer.refresh_interval {
+            self.refresh_interval = refresh_interval;
+        }
+        if layer.max_refresh_age.is_some() {
+            self.max_refresh_age = layer.max_refresh_age;
+        }
+        if let Some(static_dir) = layer.static_dir {
+            self.static_dir = static_dir;
+        }
+        if let Some(user_agent) = layer.user_agent {
+            self.user_agent = user_agent;
+        }
+        if let Some(fetch_delay_ms) = layer.fetch_delay_ms {
+            self.fetch_delay_ms = fetch_delay_ms;
+        }
+        if let Some(max_retry_wait) = layer.max_retry_wait {
+            self.max_retry_wait = max_retry_wait;
+        }
+        if layer.pages_from.is_some() {
+            self.pages_from = layer.pages_from;
+        }
+        if layer.archive_dir.is_some() {
+            self.archive_dir = layer.archive_dir;
+        }
+        if layer.admin_token.is_some() {
+            self.admin_token = layer.admin_token;
+        }
+        // Each tag's keywords replace the lower layer's, and an empty list drops the rule
+        for (tag, keywords) in layer.tag_rules {
+            if keywords.is_empty() {
+                self.tag_rules.remove(&tag);
+            } else {
+                self.tag_rules.insert(tag, keywords);
+            }
+        }
+        for (name, source) in layer.sources {
+            // Sources new to this layer are on unless they say otherwise
+            let entry = self.sources.entry(name).or_insert_with(|| SourceConfig {
+                enabled: true,
+                ..SourceConfig::default()
+            });
+            if let Some(enabled) = source.enabled {
+                entry.enabled = enabled;
+            }
+            if let Some(kind) = source.kind {
+                entry.kind = kind;
+            }
+            if source.calendar_url.is_some() {
+                entry.calendar_url = source.calendar_url;
+            }
+            if let Some(fields) = source.fields {
+                entry.fields.apply(fields);
+            }
+            if source.venue.is_some() {
+                entry.venue = source.venue;
+            }
+            if source.categories.is_some() {
+                entry.categories = source.categories;
+            }
+        }
+        if let Some(enabled) = layer.enabled_sources {
+            for (name, source) in self.sources.iter_mut() {
+                source.enabled = enabled.iter().any(|e| e.eq_ignore_ascii_case(name));
+            }
+            // Names that don't match anything are caught by validate()
+            for name in enabled {
+                if !self.sources.keys().any(|k| k.eq_ignore_ascii_case(&name)) {
+                    self.sources.insert(name, SourceConfig::default());
+                }
+            }
+        }
+    }
+    /// Reject settings that parsed but make no sense
+    fn validate(&self) -> AppResult<()> {
+        if !LOG_LEVELS.contains(&self.log_level.as_str()) {
+            return Err(AppError::Validation(format!(
+                "log_level must be one of {}, got {:?}",
+                LOG_LEVELS.join(", "),
+                self.log_level
+            )));
+        }
+        if !LOG_FORMATS.contains(&self.log_format.as_str()) {
+            return Err(AppError::Validation(format!(
+                "log_format must be one of {}, got {:?}",
+                LOG_FORMATS.join(", "),
+                self.log_format
+            )));
+        }
+        if self.database_pool_size == 0 {
+            return Err(AppError::Validation(
+                "database_pool_size must be at least 1".into(),
+            ));
+        }
+        let enabled = self.sources.values().filter(|s| s.enabled).count();
+        if self.pages_from.as_ref().is_some_and(|p| p.is_file()) && enabled > 1 {
+            return Err(AppError::Validation(format!(
+                "pages_from is a single page but {} sources are enabled - pick one with --sources, or give a directory of <Source>.html pages",
+                enabled
+            )));
+        }
+        if self.max_refresh_age == Some(0) {
+            return Err(AppError::Validation(
+                "max_refresh_age must be at least 1".into(),
+            ));
+        }
+        if self.user_agent.trim().is_empty() {
+            return Err(AppError::Validation("user_agent must not be empty".into()));
+        }
+        if self
+            .admin_token
+            .as_ref()
+            .is_some_and(|t| t.trim().is_empty())
+        {
+            return Err(AppError::Validation("admin_token must not be empty".into()));
+        }
+        for (name, source) in &self.sources {
+            let builtin = EventSource::builtin().iter().any(|s| s.as_str() == name);
+            match source.kind {
+                SourceKind::Builtin if !builtin => {
+                    return Err(AppError::Validation(format!(
+                        "unknown source {:?} - give it a kind such as \"json-ld\" and a calendar_url to add it",
+                        name
+                    )))
+                }
+                SourceKind::Builtin => {}
+                kind if builtin => {
+                    return Err(AppError::Validation(format!(
+                        "source {:?} has its own parser and can't be {}",
+                        name,
+                        kind.as_str()
+                    )))
+                }
+                kind if source.calendar_url.is_none() => {
+                    return Err(AppError::Validation(format!(
+                        "{} source {:?} needs a calendar_url",
+                        kind.as_str(),
+                        name
+                    )))
+                }
+                _ => {}
+            }
+            let feed = matches!(source.kind, SourceKind::Ical | SourceKind::Rss);
+            if !feed && !source.fields.is_empty() {
+                return Err(AppError::Validation(format!(
+                    "source {:?} isn't a feed, so its fields can't be mapped",
+                    name
+                )));
+            }
+        }
+        Ok(())
+    }
+    /// Numeric verbosity for `init_logging`
+    pub fn verbosity(&self) -> u8 {
+        LOG_LEVELS
+            .iter()
+            .position(|l| *l == self.log_level)
+            .unwrap_or(2) as u8
+    }
+    /// Settings for a source, or disabled defaults if it isn't configured
+    pub fn source(&self, source: &EventSource) -> SourceConfig {
+        self.sources
+            .get(source.as_str())
+            .cloned()
+            .unwrap_or_default()
+    }
 }
 
-/// Start env_logger
-pub fn init_logging(level: u8) -> AppResult<()> {
+/// Start env_logger, writing plain lines or one JSON object per record
+pub fn init_logging(level: u8, json: bool) -> AppResult<()> {
     // if RUST_BACKTRACE is set, ignore the arg given and set `trace` no matter what
     let mut overridden = false;
     let verbosity = if std::env::var("RUST_BACKTRACE").unwrap_or_else(|_| "0".into()) == "1" {
@@ -49,7 +465,22 @@ pub fn init_logging(level: u8) -> AppResult<()> {
     };
     set_var("RUST_LOG", format!("dalia_challenge={}", verbosity));
 
-    pretty_env_logger::init();
+    let mut builder = pretty_env_logger::formatted_builder();
+    builder.parse_filters(&var("RUST_LOG")?);
+    if json {
+        builder.format(|buf, record| {
+            let line = json_log_line(
+                &Utc::now().to_rfc3339(),
+                record.level(),
+                record.target(),
+                &record.args().to_string(),
+            );
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder
+        .try_init()
+        .map_err(|e| AppError::Internal(e.into()))?;
 
     if overridden {
         warn!("RUST_BACKTRACE is set, overriding user verbosity level");
@@ -60,3 +491,106 @@ pub fn init_logging(level: u8) -> AppResult<()> {
     info!("Set verbosity to {}", var("RUST_LOG")?);
     Ok(())
 }
+
+/// One log record as a JSON object
+/// Access log messages are already JSON objects, and their fields are merged in.
+fn json_log_line(time: &str, level: Level, target: &str, message: &str) -> String {
+    let mut line = serde_json::Map::new();
+    line.insert("time".into(), time.into());
+    line.insert("level".into(), level.as_str().into());
+    line.insert("target".into(), target.into());
+    match serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(message) {
+        Ok(fields) if target == ACCESS_LOG => line.extend(fields),
+        _ => {
+            line.insert("message".into(), message.into());
+        }
+    }
+    serde_json::Value::Object(line).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_precedence() {
+        let file = std::env::temp_dir().join(format!("dalia-config-{}.toml", std::process::id()));
+        fs::write(
+            &file,
+            "port = 4000\nlog_level = \"debug\"\n[sources.Berghain]\nenabled = false\n",
+        )
+        .unwrap();
+        let cli = OptLayer::from_iter(&[
+            "dalia-challenge",
+            "--config",
+            file.to_str().unwrap(),
+            "--port",
+            "8080",
+        ]);
+        let opt = Opt::from_layers(cli).unwrap();
+        fs::remove_file(&file).unwrap();
+
+        // CLI beats the file, the file beats the defaults
+        assert_eq!(opt.port, 8080);
+        assert_eq!(opt.verbosity(), 3);
+        assert_eq!(opt.address, "127.0.0.1");
+        assert!(!opt.source(&EventSource::Berghain(true)).enabled);
+        assert!(opt.source(&EventSource::CoBerlin(true)).enabled);
+
+        let cli = OptLayer::from_iter(&["dalia-challenge", "--sources", "berghain"]);
+        let opt = Opt::from_layers(cli).unwrap();
+        assert!(opt.source(&EventSource::Berghain(true)).enabled);
+        assert!(!opt.source(&EventSource::CoBerlin(true)).enabled);
+
+        // Several sources must be given as one comma-separated value
+        let cli = OptLayer::from_iter(&["dalia-challenge", "--sources", "berghain,coberlin"]);
+        let opt = Opt::from_layers(cli).unwrap();
+        assert!(opt.source(&EventSource::Berghain(true)).enabled);
+        assert!(opt.source(&EventSource::CoBerlin(true)).enabled);
+        let cli =
+            OptLayer::from_iter_safe(&["dalia-challenge", "--sources", "berghain", "coberlin"]);
+        assert!(cli.is_err());
+
+        let cli = OptLayer::from_iter(&["dalia-challenge", "--log-level", "loud"]);
+        assert!(Opt::from_layers(cli).is_err());
+        let cli = OptLayer::from_iter(&["dalia-challenge", "--log-format", "xml"]);
+        assert!(Opt::from_layers(cli).is_err());
+    }
+
+    #[test]
+    fn test_pages_from_file() {
+        let file = std::env::temp_dir().join(format!("dalia-page-{}.html", std::process::id()));
+        fs::write(&file, "<html></html>").unwrap();
+        let path = file.to_str().unwrap();
+        let both = OptLayer::from_iter(&["dalia-challenge", "--pages-from", path]);
+        let one = OptLayer::from_iter(&[
+            "dalia-challenge",
+            "--pages-from",
+            path,
+            "--sources",
+            "berghain",
+        ]);
+        let (both, one) = (Opt::from_layers(both), Opt::from_layers(one));
+        fs::remove_file(&file).unwrap();
+
+        // One page can't stand in for every source
+        assert!(both.is_err());
+        assert!(one.is_ok());
+        let dir = OptLayer::from_iter(&["dalia-challenge", "--pages-from", "tests"]);
+        assert!(Opt::from_layers(dir).is_ok());
+    }
+
+    #[test]
+    fn test_json_log_line() {
+        let time = "2020-02-24T12:00:00+00:00";
+        assert_eq!(
+            json_log_line(time, Level::Info, "dalia_challenge::db", "Opened {db}"),
+            r#"{"level":"INFO","message":"Opened {db}","target":"dalia_challenge::db","time":"2020-02-24T12:00:00+00:00"}"#
+        );
+        assert_eq!(
+            json_log_line(time, Level::Warn, ACCESS_LOG, r#"{"status":200}"#),
+            r#"{"level":"WARN","status":200,"target":"dalia_challenge::access","time":"2020-02-24T12:00:00+00:00"}"#
+        );
+    }
+}
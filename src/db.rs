@@ -4,15 +4,7 @@ use super::*;
 use chrono::prelude::*;
 use diesel::{prelude::*, r2d2::ConnectionManager, sql_types::Bool, sqlite::SqliteConnection};
 use diesel_migrations::*;
-use lazy_static::lazy_static;
-use std::ops::Deref;
-
-const DEFAULT_DB_URL: &str = "db.sqlite";
-
-lazy_static! {
-    pub static ref DB_POOL: Pool =
-        establish_and_run_migrations(DEFAULT_DB_URL).expect("Should create connection pool.");
-}
+use std::{collections::HashMap, ops::Deref};
 
 /// R2D2 connection pool type
 pub type Pool = r2d2::Pool<ConnectionManager<SqliteConnection>>;
@@ -29,25 +21,61 @@ impl Deref for Conn {
 }
 
 /// Connect to sqlite database and create r2d2 pool
-pub fn establish_pool(url: &str) -> AppResult<Pool> {
+pub fn establish_pool(url: &str, size: u32) -> AppResult<Pool> {
     let manager = ConnectionManager::<SqliteConnection>::new(url);
-    Ok(r2d2::Pool::new(manager)?)
+    Ok(r2d2::Pool::builder().max_size(size).build(manager)?)
 }
 
+embed_migrations!();
+
+/// Version of the newest embedded migration, as diesel records it
+const LATEST_MIGRATION: &str = "20200226120000";
+
 /// Connect to sqlite database and run the migrations
-fn establish_and_run_migrations(url: &str) -> AppResult<Pool> {
-    let pool = establish_pool(url)?;
-    embed_migrations!();
+pub fn establish_and_run_migrations(url: &str, size: u32) -> AppResult<Pool> {
+    let pool = establish_pool(url, size)?;
     embedded_migrations::run(&pool.get()?)?;
     Ok(pool)
 }
 
+/// Run any pending migrations, reporting each one applied to `out`
+pub fn run_migrations(conn: &SqliteConnection, out: &mut impl std::io::Write) -> AppResult<()> {
+    Ok(embedded_migrations::run_with_output(conn, out)?)
+}
+
+/// Whether the database has every migration this build knows of
+pub fn migrations_current(conn: &SqliteConnection) -> AppResult<bool> {
+    // Checked first, so a probe doesn't create the table the way running migrations would
+    let tracked = diesel::select(diesel::dsl::sql::<Bool>(
+        "EXISTS (SELECT 1 FROM sqlite_master WHERE name = '__diesel_schema_migrations')",
+    ))
+    .get_result::<bool>(conn)?;
+    if !tracked {
+        return Ok(false);
+    }
+    let latest = conn.latest_run_migration_version()?;
+    Ok(latest.is_some_and(|v| v.as_str() >= LATEST_MIGRATION))
+}
+
+/// Fresh migrated in-memory database, private to the calling test
+/// Every SQLite `:memory:` connection is its own database, so the pool holds just one.
+#[cfg(test)]
+pub fn test_pool() -> Pool {
+    establish_and_run_migrations(":memory:", 1).expect("Should establish test pool")
+}
+
 /// Get all currently stored events
 pub fn all_events(conn: &SqliteConnection) -> AppResult<Vec<Event>> {
     use schema::events::dsl::*;
     Ok(events.load::<Event>(conn)?)
 }
 
+/// Get one event by ID
+pub fn find_event(conn: &SqliteConnection, event_id: i32) -> AppResult<Option<Event>> {
+    use schema::events::dsl::*;
+    Ok(events.find(event_id).first(conn).optional()?)
+}
+
 /// Get the least and greatest event dates stored
 pub fn total_event_range(conn: &SqliteConnection) -> AppResult<(String, String)> {
     use schema::events::dsl::*;
@@ -62,7 +90,7 @@ pub fn total_event_range(conn: &SqliteConnection) -> AppResult<(String, String)>
     } else {
         Ok((
             NaiveDate::parse_from_str(
-                &oldest[0]
+                oldest[0]
                     .event_date
                     .split_whitespace()
                     .collect::<Vec<&str>>()[0],
@@ -71,7 +99,7 @@ pub fn total_event_range(conn: &SqliteConnection) -> AppResult<(String, String)>
             .format("%F")
             .to_string(),
             NaiveDate::parse_from_str(
-                &latest[0]
+                latest[0]
                     .event_date
                     .split_whitespace()
                     .collect::<Vec<&str>>()[0],
@@ -83,12 +111,12 @@ pub fn total_event_range(conn: &SqliteConnection) -> AppResult<(String, String)>
     }
 }
 
-/// Get a subset of events based on passed parameters
+/// Get the events matching a listing query, between the given dates
+/// Empty venue, district or category lists don't filter on them.
 pub fn filtered_events(
     begin_date: &str,
     end_date: &str,
-    src: &[EventSource],
-    title_like: &str,
+    query: &ListingQuery,
     conn: &SqliteConnection,
 ) -> AppResult<Vec<Event>> {
     use schema::events::dsl::*;
@@ -97,43 +125,318 @@ pub fn filtered_events(
     let events = events;
 
     // Filter title
-    let title_like_str = format!("%{}%", title_like);
-    let filtered = events.filter(title.like(&title_like_str));
+    let title_like_str = format!("%{}%", query.title_like);
+    let mut filtered = events.filter(title.like(&title_like_str)).into_boxed();
+
+    // Filter venue, and district by way of the venues in it
+    if !query.venues.is_empty() {
+        filtered = filtered.filter(venue.eq_any(&query.venues));
+    }
+    if !query.districts.is_empty() {
+        let in_districts = venues::table
+            .filter(venues::district.eq_any(&query.districts))
+            .select(venues::name.nullable());
+        filtered = filtered.filter(venue.eq_any(in_districts));
+    }
+
+    // Filter categories
+    if !query.categories.is_empty() {
+        let tagged = event_tags::table
+            .filter(event_tags::tag.eq_any(&query.categories))
+            .filter(event_tags::removed.eq(false))
+            .select(event_tags::event_id);
+        filtered = filtered.filter(id.eq_any(tagged));
+    }
 
     // Filter sources
     let always_false = Box::new(source.eq("Crazy Stuff"));
     // Build compound query trait object from EventSource list
-    let query: Box<dyn BoxableExpression<schema::events::table, _, SqlType = Bool>> = src
+    let sources: Box<dyn BoxableExpression<schema::events::table, _, SqlType = Bool>> = query
+        .sources
         .iter()
         .filter(|s| s.enabled())
         .map(|s| source.eq(s.as_str()))
         .fold(always_false, |query, item| Box::new(query.or(item)));
 
-    // Return filtered result set ordered by date
-    Ok(filtered
-        .filter(query)
-        .filter(event_date.between(begin_date, end_date))
+    // Timed events on the end date sort after the bare date, so extend it to the whole day
+    let end_of_day = format!("{} 23:59:59", end_date);
+
+    let found = filtered
+        .filter(sources)
+        .filter(event_date.between(begin_date, &end_of_day))
         .order(event_date)
-        .load::<Event>(conn)?)
+        .load::<Event>(conn)?;
+    if query.duplicates {
+        return Ok(found);
+    }
+
+    // Collapse duplicates into their canonical event, unless that didn't match
+    let ids = found.iter().map(|e| e.id).collect::<Vec<i32>>();
+    Ok(found
+        .into_iter()
+        .filter(|e| e.canonical_id.is_none_or(|c| !ids.contains(&c)))
+        .collect())
+}
+
+/// Counter of writes made by other processes, such as the `scrape` and `prune` subcommands
+pub fn data_version(conn: &SqliteConnection) -> AppResult<i32> {
+    use schema::data_version::dsl::*;
+    Ok(data_version.select(version).find(1).first(conn)?)
 }
 
-/// Add a new event to the database
+/// Tell running servers the stored events changed, so they drop their cached pages
+pub fn bump_data_version(conn: &SqliteConnection) -> AppResult<()> {
+    use schema::data_version::dsl::*;
+    diesel::update(data_version.find(1))
+        .set(version.eq(version + 1))
+        .execute(conn)?;
+    Ok(())
+}
+
+/// Add a new event to the database, first seen now
 pub fn create_event(conn: &SqliteConnection, new_event: NewEvent) -> AppResult<usize> {
-    Ok(diesel::insert_into(events::table)
-        .values(&new_event)
+    use schema::events::dsl::*;
+    ensure_venue(conn, new_event.venue.as_deref())?;
+    let now = Utc::now().to_rfc3339();
+    Ok(diesel::insert_into(events)
+        .values((&new_event, first_seen.eq(&now), last_updated.eq(&now)))
+        .execute(conn)?)
+}
+
+/// Overwrite a stored event, recording each changed field in its history
+pub fn update_event(
+    conn: &SqliteConnection,
+    event_id: i32,
+    event: &NewEvent,
+    changes: &[FieldChange],
+) -> AppResult<usize> {
+    use schema::events::dsl::*;
+    ensure_venue(conn, event.venue.as_deref())?;
+    let now = Utc::now().to_rfc3339();
+    let revisions = changes
+        .iter()
+        .map(|change| {
+            (
+                event_revisions::event_id.eq(event_id),
+                event_revisions::revised_dt.eq(&now),
+                event_revisions::field.eq(change.field),
+                event_revisions::old_value.eq(&change.old),
+                event_revisions::new_value.eq(&change.new),
+            )
+        })
+        .collect::<Vec<_>>();
+    diesel::insert_into(event_revisions::table)
+        .values(&revisions)
+        .execute(conn)?;
+    Ok(diesel::update(events.find(event_id))
+        .set((event, last_updated.eq(&now)))
+        .execute(conn)?)
+}
+
+/// Get an event's history, oldest change first
+pub fn revisions_of(conn: &SqliteConnection, id: i32) -> AppResult<Vec<Revision>> {
+    Ok(event_revisions::table
+        .filter(event_revisions::event_id.eq(id))
+        .order(event_revisions::id)
+        .load::<Revision>(conn)?)
+}
+
+/// Get one venue by name
+pub fn find_venue(conn: &SqliteConnection, venue_name: &str) -> AppResult<Option<Venue>> {
+    use schema::venues::dsl::*;
+    Ok(venues.find(venue_name).first(conn).optional()?)
+}
+
+/// Link an event to the one it duplicates, or unlink it with `None`
+pub fn set_canonical(
+    conn: &SqliteConnection,
+    event_id: i32,
+    canonical: Option<i32>,
+) -> AppResult<usize> {
+    use schema::events::dsl::*;
+    Ok(diesel::update(events.find(event_id))
+        .set(canonical_id.eq(canonical))
         .execute(conn)?)
 }
 
+/// Get the duplicates of each of the given events, keyed by canonical event ID
+pub fn duplicates_of(conn: &SqliteConnection, ids: &[i32]) -> AppResult<HashMap<i32, Vec<Event>>> {
+    use schema::events::dsl::*;
+    let mut ret: HashMap<i32, Vec<Event>> = HashMap::new();
+    for event in events
+        .filter(canonical_id.eq_any(ids.iter().map(|i| Some(*i))))
+        .order(id)
+        .load::<Event>(conn)?
+    {
+        if let Some(canonical) = event.canonical_id {
+            ret.entry(canonical).or_default().push(event);
+        }
+    }
+    Ok(ret)
+}
+
+/// Get every venue, by name
+pub fn all_venues(conn: &SqliteConnection) -> AppResult<Vec<Venue>> {
+    use schema::venues::dsl::*;
+    Ok(venues.order(name).load::<Venue>(conn)?)
+}
+
+/// Add a bare venue for an event to link to, unless one of that name exists
+/// Its address and district can be filled in later.
+fn ensure_venue(conn: &SqliteConnection, venue_name: Option<&str>) -> AppResult<()> {
+    use schema::venues::dsl::*;
+    if let Some(venue_name) = venue_name {
+        diesel::insert_or_ignore_into(venues)
+            .values(name.eq(venue_name))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Delete every event that finished before the given date, returning how many went
+/// Events without an end date are judged by their start.
+pub fn delete_events_before(conn: &SqliteConnection, date: NaiveDate) -> AppResult<usize> {
+    use schema::events::dsl::*;
+    let cutoff = date.format("%F").to_string();
+    let deleted = diesel::delete(
+        events.filter(
+            event_end_date
+                .lt(&cutoff)
+                .or(event_end_date.is_null().and(event_date.lt(&cutoff))),
+        ),
+    )
+    .execute(conn)?;
+    // SQLite only cascades with foreign keys switched on, which diesel leaves off
+    diesel::delete(event_tags::table.filter(event_tags::event_id.ne_all(events.select(id))))
+        .execute(conn)?;
+    diesel::delete(
+        event_revisions::table.filter(event_revisions::event_id.ne_all(events.select(id))),
+    )
+    .execute(conn)?;
+    Ok(deleted)
+}
+
+/// ID of the row most recently inserted on this connection
+pub fn last_insert_id(conn: &SqliteConnection) -> AppResult<i32> {
+    Ok(
+        diesel::select(diesel::dsl::sql::<diesel::sql_types::Integer>(
+            "last_insert_rowid()",
+        ))
+        .get_result(conn)?,
+    )
+}
+
+/// Replace an event's rule-given tags, leaving any set by hand alone
+pub fn set_auto_tags(conn: &SqliteConnection, id: i32, names: &[String]) -> AppResult<()> {
+    use schema::event_tags::dsl::*;
+    diesel::delete(event_tags.filter(event_id.eq(id)).filter(manual.eq(false))).execute(conn)?;
+    for name in names {
+        ensure_tag(conn, name)?;
+        diesel::insert_or_ignore_into(event_tags)
+            .values(EventTag {
+                event_id: id,
+                tag: name.clone(),
+                manual: false,
+                removed: false,
+            })
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Set exactly which tags an event shows, pinning them against re-tagging
+/// Tags it had that aren't listed are hidden rather than deleted, so the rules don't
+/// add them back.
+pub fn set_manual_tags(conn: &SqliteConnection, id: i32, names: &[String]) -> AppResult<()> {
+    use schema::event_tags::dsl::*;
+    let current = visible_tags(conn, &[id])?.remove(&id).unwrap_or_default();
+    for name in current.iter().filter(|t| !names.contains(t)) {
+        diesel::replace_into(event_tags)
+            .values(EventTag {
+                event_id: id,
+                tag: name.clone(),
+                manual: true,
+                removed: true,
+            })
+            .execute(conn)?;
+    }
+    for name in names {
+        ensure_tag(conn, name)?;
+        diesel::replace_into(event_tags)
+            .values(EventTag {
+                event_id: id,
+                tag: name.clone(),
+                manual: true,
+                removed: false,
+            })
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// The tags shown on each of the given events, sorted
+pub fn visible_tags(conn: &SqliteConnection, ids: &[i32]) -> AppResult<HashMap<i32, Vec<String>>> {
+    use schema::event_tags::dsl::*;
+    let rows = event_tags
+        .filter(event_id.eq_any(ids))
+        .filter(removed.eq(false))
+        .order(tag)
+        .load::<EventTag>(conn)?;
+    let mut ret: HashMap<i32, Vec<String>> = HashMap::new();
+    for row in rows {
+        ret.entry(row.event_id).or_default().push(row.tag);
+    }
+    Ok(ret)
+}
+
+/// Every tag shown on at least one event, sorted
+pub fn tags_in_use(conn: &SqliteConnection) -> AppResult<Vec<String>> {
+    use schema::event_tags::dsl::*;
+    Ok(event_tags
+        .filter(removed.eq(false))
+        .select(tag)
+        .distinct()
+        .order(tag)
+        .load::<String>(conn)?)
+}
+
+/// Add a tag for events to link to, unless it exists
+fn ensure_tag(conn: &SqliteConnection, tag_name: &str) -> AppResult<()> {
+    use schema::tags::dsl::*;
+    diesel::insert_or_ignore_into(tags)
+        .values(name.eq(tag_name))
+        .execute(conn)?;
+    Ok(())
+}
+
 /// Add a new refresh record
-pub fn create_refresh(conn: &SqliteConnection, total_added: i32) -> AppResult<usize> {
+pub fn create_refresh(
+    conn: &SqliteConnection,
+    total_added: i32,
+    sources_unchanged: i32,
+) -> AppResult<usize> {
     Ok(diesel::insert_into(refreshes::table)
         .values(NewRefresh {
             refresh_dt: &Utc::now().to_rfc3339(),
             total_added,
+            sources_unchanged,
         })
         .execute(conn)?)
 }
 
+/// Get the validators stored for a source page, if it's been fetched before
+pub fn source_page(conn: &SqliteConnection, page_url: &str) -> AppResult<Option<SourcePage>> {
+    use schema::source_pages::dsl::*;
+    Ok(source_pages.find(page_url).first(conn).optional()?)
+}
+
+/// Store the validators from the latest fetch of a source page
+pub fn save_source_page(conn: &SqliteConnection, page: &SourcePage) -> AppResult<usize> {
+    Ok(diesel::replace_into(source_pages::table)
+        .values(page)
+        .execute(conn)?)
+}
+
 /// Get the most recent refresh, if any
 pub fn latest_refresh(conn: &SqliteConnection) -> AppResult<Option<Refresh>> {
     use schema::refreshes::dsl::*;
@@ -151,11 +454,8 @@ pub fn latest_refresh(conn: &SqliteConnection) -> AppResult<Option<Refresh>> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use diesel::connection::SimpleConnection;
     use pretty_assertions::assert_eq;
-    lazy_static! {
-        pub static ref TEST_POOL: Pool =
-            establish_and_run_migrations("test.sqlite").expect("Should establish test pool");
-    }
 
     #[test]
     fn test_new_post() {
@@ -169,8 +469,182 @@ mod test {
             EventSource::CoBerlin(true),
         );
 
-        let conn = TEST_POOL.get().expect("Should get DB connection");
+        let conn = test_pool().get().expect("Should get DB connection");
 
         assert_eq!(create_event(&conn, test).unwrap(), 1)
     }
+
+    #[test]
+    fn test_filter_venues() {
+        let conn = test_pool().get().expect("Should get DB connection");
+        for venue in &["C/O Berlin", "Panorama Bar", "Radialsystem"] {
+            let event = NewEvent {
+                venue: Some(venue.to_string()),
+                ..NewEvent::new(
+                    venue,
+                    None,
+                    "#",
+                    "Synopsis",
+                    "2020-02-17",
+                    None,
+                    EventSource::CoBerlin(true),
+                )
+            };
+            create_event(&conn, event).unwrap();
+        }
+        let titles = |venues: &[String], districts: &[String]| {
+            let query = ListingQuery {
+                begin_date: None,
+                end_date: None,
+                sources: vec![EventSource::CoBerlin(true)],
+                title_like: "%".into(),
+                venues: venues.to_vec(),
+                districts: districts.to_vec(),
+                categories: Vec::new(),
+                duplicates: false,
+            };
+            filtered_events("2020-01-01", "2020-12-31", &query, &conn)
+                .unwrap()
+                .into_iter()
+                .map(|e| e.title)
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(titles(&[], &[]).len(), 3);
+        assert_eq!(titles(&["Radialsystem".into()], &[]), vec!["Radialsystem"]);
+        assert_eq!(
+            titles(&[], &["Friedrichshain".into()]),
+            vec!["Panorama Bar"]
+        );
+        assert!(titles(&["C/O Berlin".into()], &["Friedrichshain".into()]).is_empty());
+
+        // Unknown venues are added without any details
+        let radialsystem = all_venues(&conn)
+            .unwrap()
+            .into_iter()
+            .find(|v| v.name == "Radialsystem")
+            .expect("Should add the venue");
+        assert_eq!(radialsystem.district, None);
+    }
+
+    #[test]
+    fn test_end_date_includes_whole_day() {
+        let conn = test_pool().get().expect("Should get DB connection");
+        for (title, date) in &[
+            ("Klubnacht", "2020-02-21 23:59:00"),
+            ("Opening", "2020-02-21"),
+            ("Matinee", "2020-02-22 11:00:00"),
+        ] {
+            let event = NewEvent::new(
+                title,
+                None,
+                "#",
+                "",
+                date,
+                None,
+                EventSource::Berghain(true),
+            );
+            create_event(&conn, event).unwrap();
+        }
+        let query = ListingQuery {
+            begin_date: None,
+            end_date: None,
+            sources: vec![EventSource::Berghain(true)],
+            title_like: "%".into(),
+            venues: Vec::new(),
+            districts: Vec::new(),
+            categories: Vec::new(),
+            duplicates: false,
+        };
+        let titles = filtered_events("2020-02-21", "2020-02-21", &query, &conn)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.title)
+            .collect::<Vec<String>>();
+        assert_eq!(titles, vec!["Opening", "Klubnacht"]);
+    }
+
+    #[test]
+    fn test_migrations_current() {
+        let newest = std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/migrations"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .max()
+            .unwrap();
+        let version = newest.split('_').next().unwrap().replace('-', "");
+        assert_eq!(version, LATEST_MIGRATION, "Update LATEST_MIGRATION");
+
+        let conn = establish_pool(":memory:", 1).unwrap().get().unwrap();
+        assert!(!migrations_current(&conn).unwrap());
+        run_migrations(&conn, &mut std::io::sink()).unwrap();
+        assert!(migrations_current(&conn).unwrap());
+    }
+
+    #[test]
+    fn test_normalize_hrefs_migration() {
+        let conn = test_pool().get().expect("Should get DB connection");
+        for href in &[
+            "http://www.co-berlin.org/en/calender",
+            "http://www.co-berlin.org//en/calender",
+            "http://www.co-berlin.org/https://www.co-berlin.org/en/calender",
+            "http://berghain.de/http://berghain.de/en/event/1",
+            "https://elsewhere.example/",
+        ] {
+            let event = NewEvent::new(
+                "Test Event",
+                None,
+                href,
+                "Synopsis",
+                "2020-02-17",
+                None,
+                EventSource::CoBerlin(true),
+            );
+            create_event(&conn, event).unwrap();
+        }
+
+        conn.batch_execute(include_str!(
+            "../migrations/2020-02-21-120000_normalize_hrefs/up.sql"
+        ))
+        .unwrap();
+        let hrefs = all_events(&conn)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.href)
+            .collect::<Vec<String>>();
+        assert_eq!(
+            hrefs,
+            vec![
+                "https://www.co-berlin.org/en/calender",
+                "https://www.co-berlin.org/en/calender",
+                "https://www.co-berlin.org/en/calender",
+                "https://berghain.de/en/event/1",
+                "https://elsewhere.example/",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_delete_events_before() {
+        let conn = test_pool().get().expect("Should get DB connection");
+        for (date, end_date) in &[
+            ("2020-01-01", None),
+            ("2020-01-01", Some("2020-03-01".to_string())),
+            ("2020-02-01 20:00:00", None),
+        ] {
+            let event = NewEvent::new(
+                "Test Event",
+                None,
+                "#",
+                "Synopsis",
+                date,
+                end_date.clone(),
+                EventSource::CoBerlin(true),
+            );
+            create_event(&conn, event).unwrap();
+        }
+
+        let cutoff = NaiveDate::from_ymd_opt(2020, 2, 1).unwrap();
+        assert_eq!(delete_events_before(&conn, cutoff).unwrap(), 1);
+        assert_eq!(all_events(&conn).unwrap().len(), 2);
+    }
 }
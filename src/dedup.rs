@@ -0,0 +1,330 @@
+// dedup.rs
+// Spotting the same event listed by more than one source
+
+use super::*;
+use diesel::{sqlite::SqliteConnection, Connection};
+use std::collections::{HashMap, HashSet};
+
+/// Titles at least this similar, by Dice coefficient over character pairs, may be one event
+const TITLE_THRESHOLD: f64 = 0.8;
+
+/// First and last day of a written event, as YYYY-MM-DD
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DayRange {
+    pub first: String,
+    pub last: String,
+}
+
+impl DayRange {
+    pub fn new(event_date: &str, event_end_date: Option<&String>) -> Self {
+        let (first, last) = days(event_date, event_end_date);
+        Self {
+            first: first.into(),
+            last: last.into(),
+        }
+    }
+    fn overlaps(&self, event: &Event) -> bool {
+        let (first, last) = days(&event.event_date, event.event_end_date.as_ref());
+        self.first.as_str() <= last && first <= self.last.as_str()
+    }
+}
+
+/// What two listings are compared on
+#[derive(Debug)]
+struct Listing<'a> {
+    event: &'a Event,
+    /// Character pairs of the normalized title
+    bigrams: HashSet<(char, char)>,
+    /// First and last day, as YYYY-MM-DD
+    first_day: &'a str,
+    last_day: &'a str,
+}
+
+impl<'a> Listing<'a> {
+    fn new(event: &'a Event) -> Self {
+        let (first_day, last_day) = days(&event.event_date, event.event_end_date.as_ref());
+        Self {
+            event,
+            bigrams: bigrams(&normalize_title(&event.title)),
+            first_day,
+            last_day,
+        }
+    }
+    /// Whether these look like one event listed by two sources
+    /// A missing venue matches any, since aggregators often don't name one.
+    fn same_event(&self, other: &Listing) -> bool {
+        let (a, b) = (self.event, other.event);
+        a.source != b.source
+            && self.first_day <= other.last_day
+            && other.first_day <= self.last_day
+            && (a.venue.is_none() || b.venue.is_none() || a.venue == b.venue)
+            && dice(&self.bigrams, &other.bigrams) >= TITLE_THRESHOLD
+    }
+}
+
+/// Cluster every stored event with its listings from other sources, and link each
+/// duplicate to the cluster's canonical event
+/// The canonical event is the earliest stored one that names a venue, or else the
+/// earliest stored. Returns how many events' links changed.
+pub fn dedup_events(conn: &SqliteConnection) -> AppResult<usize> {
+    let mut events = all_events(conn)?;
+    events.sort_by_key(|e| e.id);
+    link_duplicates(conn, &events)
+}
+
+/// Like `dedup_events`, but only for events on the days of those just written
+/// Their existing clusters are taken whole, so a new canonical event relinks every member.
+pub fn dedup_events_around(conn: &SqliteConnection, written: &[DayRange]) -> AppResult<usize> {
+    let mut events = all_events(conn)?;
+    events.sort_by_key(|e| e.id);
+    let cluster = |e: &Event| e.canonical_id.unwrap_or(e.id);
+    let touched = events
+        .iter()
+        .filter(|e| written.iter().any(|days| days.overlaps(e)))
+        .map(cluster)
+        .collect::<HashSet<i32>>();
+    events.retain(|e| touched.contains(&cluster(e)));
+    link_duplicates(conn, &events)
+}
+
+/// Cluster the given events, which must be in ID order, and store each one's link
+fn link_duplicates(conn: &SqliteConnection, events: &[Event]) -> AppResult<usize> {
+    let listings = events.iter().map(Listing::new).collect::<Vec<Listing>>();
+
+    // Union-find over every matching pair
+    let mut parent = (0..listings.len()).collect::<Vec<usize>>();
+    fn root(parent: &mut [usize], mut i: usize) -> usize {
+        while parent[i] != i {
+            parent[i] = parent[parent[i]];
+            i = parent[i];
+        }
+        i
+    }
+    // Venues and sources in each cluster, kept at its root
+    let mut venues = events
+        .iter()
+        .map(|e| e.venue.iter().map(String::as_str).collect())
+        .collect::<Vec<HashSet<&str>>>();
+    let mut sources = events
+        .iter()
+        .map(|e| std::iter::once(e.source.as_str()).collect())
+        .collect::<Vec<HashSet<&str>>>();
+    for i in 0..listings.len() {
+        for j in i + 1..listings.len() {
+            if !listings[i].same_event(&listings[j]) {
+                continue;
+            }
+            let (a, b) = (root(&mut parent, i), root(&mut parent, j));
+            // Matches chain, so a venueless listing could otherwise join two venues' events
+            if a == b
+                || venues[a].union(&venues[b]).count() > 1
+                || !sources[a].is_disjoint(&sources[b])
+            {
+                continue;
+            }
+            let (keep, merged) = (a.min(b), a.max(b));
+            parent[merged] = keep;
+            let merged_venues = std::mem::take(&mut venues[merged]);
+            venues[keep].extend(merged_venues);
+            let merged_sources = std::mem::take(&mut sources[merged]);
+            sources[keep].extend(merged_sources);
+        }
+    }
+
+    let mut canonical: HashMap<usize, &Event> = HashMap::new();
+    for (i, event) in events.iter().enumerate() {
+        let cluster = root(&mut parent, i);
+        let best = canonical.entry(cluster).or_insert(event);
+        // Events are in ID order, so only a venue can displace the first
+        if best.venue.is_none() && event.venue.is_some() {
+            *best = event;
+        }
+    }
+
+    conn.transaction::<_, AppError, _>(|| {
+        let mut changed = 0;
+        for (i, event) in events.iter().enumerate() {
+            let best = canonical[&root(&mut parent, i)];
+            let canonical_id = Some(best.id).filter(|id| *id != event.id);
+            if canonical_id != event.canonical_id {
+                set_canonical(conn, event.id, canonical_id)?;
+                changed += 1;
+            }
+        }
+        Ok(changed)
+    })
+}
+
+/// Lower-cased words of a title, without punctuation
+fn normalize_title(title: &str) -> String {
+    title
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+/// Every pair of adjacent characters
+fn bigrams(s: &str) -> HashSet<(char, char)> {
+    s.chars().zip(s.chars().skip(1)).collect()
+}
+
+/// Dice coefficient: 1 for the same pairs, 0 for none shared
+fn dice(a: &HashSet<(char, char)>, b: &HashSet<(char, char)>) -> f64 {
+    if a.is_empty() && b.is_empty() {
+        return 1.0;
+    }
+    2.0 * a.intersection(b).count() as f64 / (a.len() + b.len()) as f64
+}
+
+/// The date part of a stored date or date-time
+fn day(stored: &str) -> &str {
+    stored.get(..10).unwrap_or(stored)
+}
+
+/// First and last day of an event
+fn days<'a>(event_date: &'a str, event_end_date: Option<&'a String>) -> (&'a str, &'a str) {
+    let last = event_end_date.map(String::as_str).unwrap_or(event_date);
+    (day(event_date), day(last))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_dedup() {
+        let conn = test_pool().get().expect("Should get DB connection");
+        let listings = [
+            (
+                "Light and Shadow",
+                "2020-03-01",
+                Some("2020-04-30"),
+                EventSource::CoBerlin(true),
+                Some("C/O Berlin"),
+            ),
+            (
+                "LIGHT & SHADOW",
+                "2020-03-14 19:00:00",
+                None,
+                EventSource::Rss("Guide".into(), true),
+                None,
+            ),
+            (
+                "Light and Shadow",
+                "2020-03-14",
+                None,
+                EventSource::Rss("Guide".into(), true),
+                Some("Elsewhere"),
+            ),
+            (
+                "Klubnacht",
+                "2020-03-06 23:59:00",
+                None,
+                EventSource::Berghain(true),
+                Some("Berghain"),
+            ),
+            (
+                "Klubnacht",
+                "2020-03-13 23:59:00",
+                None,
+                EventSource::Ical("Club".into(), true),
+                None,
+            ),
+        ];
+        for (title, date, end, source, venue) in &listings {
+            let event = NewEvent {
+                venue: venue.map(String::from),
+                ..NewEvent::new(
+                    title,
+                    None,
+                    "#",
+                    "",
+                    date,
+                    end.map(String::from),
+                    source.clone(),
+                )
+            };
+            create_event(&conn, event).unwrap();
+        }
+
+        assert_eq!(dedup_events(&conn).unwrap(), 1);
+        let mut events = all_events(&conn).unwrap();
+        events.sort_by_key(|e| e.id);
+        let links = events
+            .iter()
+            .map(|e| e.canonical_id)
+            .collect::<Vec<Option<i32>>>();
+        let first = events[0].id;
+        assert_eq!(links, vec![None, Some(first), None, None, None]);
+
+        // Running again changes nothing
+        assert_eq!(dedup_events(&conn).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_dedup_conflicts() {
+        let conn = test_pool().get().expect("Should get DB connection");
+        let listings = [
+            ("Klubnacht", EventSource::Berghain(true), Some("Berghain")),
+            (
+                "Klubnacht",
+                EventSource::Ical("Club".into(), true),
+                Some("Tresor"),
+            ),
+            ("Klubnacht", EventSource::Rss("Guide".into(), true), None),
+            ("Klubnacht!", EventSource::Rss("Guide".into(), true), None),
+        ];
+        for (title, source, venue) in &listings {
+            let event = NewEvent {
+                venue: venue.map(String::from),
+                ..NewEvent::new(title, None, "#", "", "2020-03-06", None, source.clone())
+            };
+            create_event(&conn, event).unwrap();
+        }
+
+        // Each guide listing matches both venues' events, but only one can join each
+        assert_eq!(dedup_events(&conn).unwrap(), 2);
+        let mut events = all_events(&conn).unwrap();
+        events.sort_by_key(|e| e.id);
+        let links = events
+            .iter()
+            .map(|e| e.canonical_id)
+            .collect::<Vec<Option<i32>>>();
+        assert_eq!(
+            links,
+            vec![None, None, Some(events[0].id), Some(events[1].id)]
+        );
+    }
+
+    #[test]
+    fn test_dedup_around() {
+        let conn = test_pool().get().expect("Should get DB connection");
+        let listings = [
+            ("Klubnacht", "2020-03-06", EventSource::Berghain(true)),
+            (
+                "Klubnacht",
+                "2020-03-06",
+                EventSource::Rss("Guide".into(), true),
+            ),
+            ("Film Club", "2020-03-20", EventSource::CoBerlin(true)),
+            (
+                "Film Club",
+                "2020-03-20",
+                EventSource::Rss("Guide".into(), true),
+            ),
+        ];
+        for (title, date, source) in &listings {
+            let event = NewEvent::new(title, None, "#", "", date, None, source.clone());
+            create_event(&conn, event).unwrap();
+        }
+
+        // Only the written day is looked at
+        let written = [DayRange::new("2020-03-06 23:59:00", None)];
+        assert_eq!(dedup_events_around(&conn, &written).unwrap(), 1);
+        assert_eq!(dedup_events(&conn).unwrap(), 1);
+        assert_eq!(dedup_events_around(&conn, &written).unwrap(), 0);
+    }
+}
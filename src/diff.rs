@@ -0,0 +1,247 @@
+// diff.rs
+// What a scrape would change, compared to the stored events
+
+use super::*;
+use diesel::sqlite::SqliteConnection;
+use log::info;
+use serde_derive::Serialize;
+use std::fmt;
+
+/// A field whose scraped value differs from the stored one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldChange {
+    pub field: &'static str,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// A stored event the scrape would rewrite
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct EventUpdate {
+    pub id: i32,
+    pub event: NewEvent,
+    pub changes: Vec<FieldChange>,
+}
+
+/// The outcome of scraping one source, before anything is written
+/// Scraped events are matched to stored ones by source, link and start date.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ScrapeDiff {
+    pub source: String,
+    pub inserted: Vec<NewEvent>,
+    pub updated: Vec<EventUpdate>,
+    pub unchanged: Vec<Event>,
+    /// Items on the page that couldn't be read as events
+    pub failed: usize,
+}
+
+impl ScrapeDiff {
+    /// Compare freshly parsed events from `source` against everything stored
+    pub fn new(source: &EventSource, stored: &[Event], parsed: ParsedEvents) -> Self {
+        let mut ret = Self {
+            source: source.as_str().into(),
+            inserted: Vec::new(),
+            updated: Vec::new(),
+            unchanged: Vec::new(),
+            failed: parsed.failed,
+        };
+        for event in parsed.events {
+            let existing = stored.iter().find(|s| {
+                s.source == event.source && s.href == event.href && s.event_date == event.event_date
+            });
+            match existing {
+                None => ret.inserted.push(event),
+                Some(old) => {
+                    let changes = field_changes(old, &event);
+                    if changes.is_empty() {
+                        ret.unchanged.push(old.clone());
+                    } else {
+                        ret.updated.push(EventUpdate {
+                            id: old.id,
+                            event,
+                            changes,
+                        });
+                    }
+                }
+            }
+        }
+        ret
+    }
+    /// Days of the events `apply` would write
+    pub fn written_days(&self) -> Vec<DayRange> {
+        self.inserted
+            .iter()
+            .chain(self.updated.iter().map(|update| &update.event))
+            .map(|event| DayRange::new(&event.event_date, event.event_end_date.as_ref()))
+            .collect()
+    }
+    /// Write the inserts and updates, tagging each event written
+    /// Returns how many events were added and how many updated
+    pub fn apply(
+        self,
+        conn: &SqliteConnection,
+        tagger: &Tagger,
+        metrics: &Metrics,
+    ) -> AppResult<(usize, usize)> {
+        let mut ret = 0;
+        for event in self.inserted {
+            let tags = tagger.tags(&event);
+            ret += metrics.timed("create_event", || create_event(conn, event))?;
+            set_auto_tags(conn, last_insert_id(conn)?, &tags)?;
+        }
+        for update in &self.updated {
+            metrics.timed("update_event", || {
+                update_event(conn, update.id, &update.event, &update.changes)
+            })?;
+            tagger.tag(conn, update.id, &update.event)?;
+        }
+        if !self.updated.is_empty() {
+            info!("Updated {} {} events", self.updated.len(), self.source);
+        }
+        Ok((ret, self.updated.len()))
+    }
+}
+
+/// Every compared field that differs between a stored event and its scraped match
+fn field_changes(old: &Event, new: &NewEvent) -> Vec<FieldChange> {
+    let fields = [
+        ("title", Some(&old.title), Some(&new.title)),
+        ("subtitle", old.subtitle.as_ref(), new.subtitle.as_ref()),
+        ("synopsis", Some(&old.synopsis), Some(&new.synopsis)),
+        (
+            "event_end_date",
+            old.event_end_date.as_ref(),
+            new.event_end_date.as_ref(),
+        ),
+        ("venue", old.venue.as_ref(), new.venue.as_ref()),
+    ];
+    fields
+        .iter()
+        .filter(|(_, old, new)| old != new)
+        .map(|(field, old, new)| FieldChange {
+            field,
+            old: old.cloned(),
+            new: new.cloned(),
+        })
+        .collect()
+}
+
+impl fmt::Display for ScrapeDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} to insert, {} to update, {} unchanged, {} unreadable",
+            self.source,
+            self.inserted.len(),
+            self.updated.len(),
+            self.unchanged.len(),
+            self.failed
+        )?;
+        for event in &self.inserted {
+            writeln!(f, "  +       {:<19}  {}", event.event_date, event.title)?;
+        }
+        for update in &self.updated {
+            writeln!(
+                f,
+                "  ~ {:>5} {:<19}  {}",
+                update.id, update.event.event_date, update.event.title
+            )?;
+            for change in &update.changes {
+                writeln!(
+                    f,
+                    "        {}: {:?} -> {:?}",
+                    change.field, change.old, change.new
+                )?;
+            }
+        }
+        for event in &self.unchanged {
+            writeln!(
+                f,
+                "  = {:>5} {:<19}  {}",
+                event.id, event.event_date, event.title
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_diff() {
+        let conn = test_pool().get().expect("Should get DB connection");
+        let source = EventSource::Berghain(true);
+        for title in &["Klubnacht", "Panorama Bar"] {
+            let href = format!("#{}", title);
+            let event = NewEvent::new(
+                title,
+                None,
+                &href,
+                "Synopsis",
+                "2020-02-21",
+                None,
+                source.clone(),
+            );
+            create_event(&conn, event).unwrap();
+        }
+
+        let parsed = vec![
+            NewEvent::new(
+                "Klubnacht",
+                None,
+                "#Klubnacht",
+                "Synopsis",
+                "2020-02-21",
+                None,
+                source.clone(),
+            ),
+            NewEvent::new(
+                "Panorama Bar",
+                Some("Late".into()),
+                "#Panorama Bar",
+                "Synopsis",
+                "2020-02-21",
+                None,
+                source.clone(),
+            ),
+            NewEvent::new(
+                "Säule",
+                None,
+                "#Säule",
+                "Synopsis",
+                "2020-02-22",
+                None,
+                source.clone(),
+            ),
+        ];
+        let parsed = ParsedEvents {
+            events: parsed,
+            failed: 0,
+        };
+        let diff = ScrapeDiff::new(&source, &all_events(&conn).unwrap(), parsed);
+        assert_eq!(diff.inserted.len(), 1);
+        assert_eq!(diff.unchanged.len(), 1);
+        assert_eq!(
+            diff.updated[0].changes,
+            vec![FieldChange {
+                field: "subtitle",
+                old: None,
+                new: Some("Late".into()),
+            }]
+        );
+
+        // Nothing was written until now
+        assert_eq!(all_events(&conn).unwrap().len(), 2);
+        assert_eq!(
+            diff.apply(&conn, &Tagger::default(), &Metrics::default())
+                .unwrap(),
+            (1, 1)
+        );
+        let stored = all_events(&conn).unwrap();
+        assert_eq!(stored.len(), 3);
+        assert_eq!(stored[1].subtitle, Some("Late".into()));
+    }
+}
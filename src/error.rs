@@ -0,0 +1,111 @@
+// error.rs
+// Application error type, classifying failures by cause
+
+use hyper::StatusCode;
+use log::Level;
+use std::fmt;
+
+/// Crate-wide result type
+pub type AppResult<T> = Result<T, AppError>;
+
+/// Every error the app can produce, split by what went wrong
+#[derive(Debug)]
+pub enum AppError {
+    /// The user sent something we can't use
+    Validation(String),
+    /// The requested thing doesn't exist
+    NotFound(String),
+    /// The request lacked valid credentials for an admin route
+    Unauthorized(String),
+    /// The database or its pool failed
+    Database(anyhow::Error),
+    /// An event source couldn't be reached
+    Upstream(anyhow::Error),
+    /// An event source sent a page we couldn't understand - most likely its markup changed
+    Parse(anyhow::Error),
+    /// Anything else - a bug or an environment problem on our end
+    Internal(anyhow::Error),
+}
+
+impl AppError {
+    /// Parse failure with a description of what was missing or malformed
+    pub fn parse(context: impl fmt::Display) -> Self {
+        AppError::Parse(anyhow::anyhow!("{}", context))
+    }
+    /// Status code to respond with
+    pub fn status(&self) -> StatusCode {
+        use AppError::*;
+        match self {
+            Validation(_) => StatusCode::BAD_REQUEST,
+            NotFound(_) => StatusCode::NOT_FOUND,
+            Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Database(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Upstream(_) | Parse(_) => StatusCode::BAD_GATEWAY,
+            Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+    /// Level to log at - user mistakes aren't worth an error line
+    pub fn log_level(&self) -> Level {
+        use AppError::*;
+        match self {
+            Validation(_) | NotFound(_) | Unauthorized(_) => Level::Info,
+            Upstream(_) => Level::Warn,
+            Database(_) | Parse(_) | Internal(_) => Level::Error,
+        }
+    }
+    /// Message safe to show to the user
+    pub fn user_message(&self) -> &str {
+        use AppError::*;
+        match self {
+            Validation(msg) | NotFound(msg) | Unauthorized(msg) => msg,
+            Database(_) => "The event database is unavailable right now.",
+            Upstream(_) => "One of the event sources could not be reached.",
+            Parse(_) => "One of the event sources sent a page we couldn't read.",
+            Internal(_) => "Something went wrong on our end.",
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use AppError::*;
+        match self {
+            Validation(msg) => write!(f, "validation error: {}", msg),
+            NotFound(msg) => write!(f, "not found: {}", msg),
+            Unauthorized(msg) => write!(f, "unauthorized: {}", msg),
+            Database(e) => write!(f, "database error: {:#}", e),
+            Upstream(e) => write!(f, "upstream fetch error: {:#}", e),
+            Parse(e) => write!(f, "parse error: {:#}", e),
+            Internal(e) => write!(f, "internal error: {:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+/// Implement From for each listed error type, wrapping it in the given variant
+macro_rules! classify {
+    ($variant:ident: $($ty:ty),+ $(,)?) => {
+        $(
+            impl From<$ty> for AppError {
+                fn from(e: $ty) -> Self {
+                    AppError::$variant(e.into())
+                }
+            }
+        )+
+    };
+}
+
+classify!(Database: diesel::result::Error, diesel_migrations::RunMigrationsError, r2d2::Error);
+classify!(Upstream: reqwest::Error);
+classify!(
+    Internal: anyhow::Error,
+    askama::Error,
+    chrono::ParseError,
+    hyper::Error,
+    hyper::http::Error,
+    hyper::header::InvalidHeaderValue,
+    std::env::VarError,
+    std::io::Error,
+    std::time::SystemTimeError,
+);
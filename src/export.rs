@@ -0,0 +1,204 @@
+// export.rs
+// Reading and writing events in interchange formats
+
+use super::*;
+use chrono::prelude::*;
+use std::{fmt, io, str::FromStr};
+
+/// Formats events can be exported to or imported from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+    Ics,
+}
+
+impl Format {
+    /// Names accepted on the command line
+    pub const NAMES: &'static [&'static str] = &["json", "csv", "ics"];
+}
+
+impl FromStr for Format {
+    type Err = AppError;
+
+    fn from_str(s: &str) -> AppResult<Self> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "ics" => Ok(Format::Ics),
+            _ => Err(AppError::Validation(format!(
+                "format must be one of {}, got {:?}",
+                Format::NAMES.join(", "),
+                s
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::Ics => "ics",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Write events in the given format
+pub fn write_events(events: &[Event], format: Format, out: &mut impl io::Write) -> AppResult<()> {
+    match format {
+        Format::Json => {
+            serde_json::to_writer_pretty(&mut *out, events)
+                .map_err(|e| AppError::Internal(e.into()))?;
+            writeln!(out)?;
+        }
+        Format::Csv => {
+            let mut writer = csv::Writer::from_writer(out);
+            for event in events {
+                writer
+                    .serialize(event)
+                    .map_err(|e| AppError::Internal(e.into()))?;
+            }
+            writer.flush()?;
+        }
+        Format::Ics => write_ics(events, out)?,
+    }
+    Ok(())
+}
+
+/// Read events previously written with `write_events`
+/// Stored IDs are ignored - imported events are always given fresh ones.
+pub fn read_events(format: Format, input: impl io::Read) -> AppResult<Vec<NewEvent>> {
+    match format {
+        Format::Json => serde_json::from_reader(input)
+            .map_err(|e| AppError::Validation(format!("invalid JSON export: {}", e))),
+        Format::Csv => csv::Reader::from_reader(input)
+            .deserialize()
+            .collect::<Result<Vec<NewEvent>, _>>()
+            .map_err(|e| AppError::Validation(format!("invalid CSV export: {}", e))),
+        Format::Ics => Err(AppError::Validation(
+            "importing from ics is not supported".into(),
+        )),
+    }
+}
+
+/// Write events as an iCalendar VCALENDAR
+fn write_ics(events: &[Event], out: &mut impl io::Write) -> AppResult<()> {
+    let stamp = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".into(),
+        format!("PRODID:-//{}//EN", env!("CARGO_PKG_NAME")),
+    ];
+    for event in events {
+        lines.push("BEGIN:VEVENT".into());
+        lines.push(format!("UID:{}@{}", event.id, env!("CARGO_PKG_NAME")));
+        lines.push(format!("DTSTAMP:{}", stamp));
+        lines.push(ics_date("DTSTART", &event.event_date, false)?);
+        if let Some(end) = &event.event_end_date {
+            lines.push(ics_date("DTEND", end, true)?);
+        }
+        lines.push(format!("SUMMARY:{}", ics_escape(&event.title)));
+        let description = match &event.subtitle {
+            Some(subtitle) => format!("{}\n\n{}", subtitle, event.synopsis),
+            None => event.synopsis.clone(),
+        };
+        lines.push(format!("DESCRIPTION:{}", ics_escape(&description)));
+        if let Some(venue) = &event.venue {
+            lines.push(format!("LOCATION:{}", ics_escape(venue)));
+        }
+        lines.push(format!("URL:{}", event.href));
+        lines.push(format!("CATEGORIES:{}", ics_escape(&event.source)));
+        lines.push("END:VEVENT".into());
+    }
+    lines.push("END:VCALENDAR".into());
+    for line in lines {
+        write!(out, "{}\r\n", ics_fold(&line))?;
+    }
+    Ok(())
+}
+
+/// Stored date or date-time as an iCalendar property
+/// All-day end dates are exclusive in iCalendar, so those move forward a day.
+fn ics_date(name: &str, stored: &str, is_end: bool) -> AppResult<String> {
+    if let Ok(dt) = NaiveDateTime::parse_from_str(stored, "%F %T") {
+        return Ok(format!("{}:{}", name, dt.format("%Y%m%dT%H%M%S")));
+    }
+    let mut date = NaiveDate::parse_from_str(stored, "%F")?;
+    if is_end {
+        date = date.succ_opt().unwrap_or(date);
+    }
+    Ok(format!("{};VALUE=DATE:{}", name, date.format("%Y%m%d")))
+}
+
+/// Escape text for an iCalendar property value
+fn ics_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\r', "")
+        .replace('\n', "\\n")
+}
+
+/// Fold a content line to at most 75 octets, per RFC 5545
+fn ics_fold(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len());
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            width = 1;
+        }
+        folded.push(c);
+        width += c.len_utf8();
+    }
+    folded
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    fn event() -> Event {
+        Event {
+            id: 7,
+            href: "https://example.com/a".into(),
+            title: "Light, Shadow; and more".into(),
+            subtitle: None,
+            synopsis: "x".repeat(100),
+            event_date: "2020-03-01".into(),
+            event_end_date: Some("2020-04-30".into()),
+            source: "CoBerlin".into(),
+            venue: Some("C/O Berlin".into()),
+            canonical_id: None,
+            first_seen: None,
+            last_updated: None,
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for format in &[Format::Json, Format::Csv] {
+            let mut out = Vec::new();
+            write_events(&[event()], *format, &mut out).unwrap();
+            let read = read_events(*format, out.as_slice()).unwrap();
+            assert_eq!(read.len(), 1);
+            assert!(event() == read[0]);
+        }
+    }
+
+    #[test]
+    fn test_ics() {
+        let mut out = Vec::new();
+        write_events(&[event()], Format::Ics, &mut out).unwrap();
+        let ics = String::from_utf8(out).unwrap();
+        assert!(ics.contains("DTSTART;VALUE=DATE:20200301\r\n"));
+        assert!(ics.contains("DTEND;VALUE=DATE:20200501\r\n"));
+        assert!(ics.contains("SUMMARY:Light\\, Shadow\\; and more\r\n"));
+        assert!(ics.contains("LOCATION:C/O Berlin\r\n"));
+        assert!(ics.lines().all(|l| l.len() <= 75));
+    }
+}
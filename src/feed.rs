@@ -0,0 +1,484 @@
+// feed.rs
+// Events from iCalendar and RSS/Atom feeds
+
+use super::*;
+use chrono::prelude::*;
+use chrono_tz::Europe::Berlin;
+use log::{debug, warn};
+use roxmltree::Node;
+use select::{document::Document, predicate::Name};
+
+/// Every VEVENT in an iCalendar feed, apart from cancelled ones
+pub fn parse_ical_events(
+    page: &Page,
+    source: &EventSource,
+    fields: &FieldMap,
+) -> AppResult<ParsedEvents> {
+    let mut ret = ParsedEvents::default();
+    let mut event: Option<Vec<Property>> = None;
+    // Components inside the VEVENT, such as VALARM, whose properties aren't the event's
+    let mut nested = 0;
+    for line in unfold(&page.body) {
+        let property = match Property::parse(&line) {
+            Some(property) => property,
+            None => continue,
+        };
+        match (property.name.as_str(), event.as_mut()) {
+            ("BEGIN", None) if property.value.eq_ignore_ascii_case("VEVENT") => {
+                event = Some(Vec::new())
+            }
+            ("BEGIN", Some(_)) => nested += 1,
+            ("END", Some(_)) if nested > 0 => nested -= 1,
+            ("END", Some(properties)) => {
+                // One broken VEVENT shouldn't hide the rest of the calendar
+                match ical_event(properties, page, source, fields) {
+                    Ok(Some(e)) => ret.events.push(e),
+                    Ok(None) => {}
+                    Err(e) => {
+                        warn!("{}: skipping VEVENT: {}", source.as_str(), e);
+                        ret.failed += 1;
+                    }
+                }
+                event = None;
+            }
+            (_, Some(properties)) if nested == 0 => properties.push(property),
+            _ => {}
+        }
+    }
+    Ok(ret)
+}
+
+/// Every item in an RSS feed, or entry in an Atom feed
+pub fn parse_rss_events(
+    page: &Page,
+    source: &EventSource,
+    fields: &FieldMap,
+) -> AppResult<ParsedEvents> {
+    let document = roxmltree::Document::parse(&page.body)
+        .map_err(|e| AppError::parse(format!("bad feed XML: {}", e)))?;
+    let mut ret = ParsedEvents::default();
+    let items = document
+        .descendants()
+        .filter(|n| n.is_element() && matches!(n.tag_name().name(), "item" | "entry"));
+    for item in items {
+        match feed_item(item, page, source, fields) {
+            Ok(e) => ret.events.push(e),
+            Err(e) => {
+                warn!("{}: skipping feed item: {}", source.as_str(), e);
+                ret.failed += 1;
+            }
+        }
+    }
+    if fields.start.is_none() && !ret.events.is_empty() {
+        warn!(
+            "{}: no fields.start, so events start at their items' publication dates",
+            source.as_str()
+        );
+    }
+    Ok(ret)
+}
+
+/// One iCalendar content line, e.g. `DTSTART;TZID=Europe/Berlin:20200306T210000`
+#[derive(Debug)]
+struct Property {
+    /// Upper-cased name, without parameters
+    name: String,
+    value: String,
+}
+
+impl Property {
+    /// Split a line at the first colon outside a quoted parameter value
+    fn parse(line: &str) -> Option<Self> {
+        let mut quoted = false;
+        let colon = line.char_indices().find_map(|(i, c)| match c {
+            '"' => {
+                quoted = !quoted;
+                None
+            }
+            ':' if !quoted => Some(i),
+            _ => None,
+        })?;
+        let name = line[..colon].split(';').next().unwrap_or_default();
+        Some(Self {
+            name: name.trim().to_uppercase(),
+            value: line[colon + 1..].to_string(),
+        })
+    }
+    /// The value as text, with escapes undone
+    fn text(&self) -> Option<String> {
+        let mut ret = String::new();
+        let mut chars = self.value.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('\\', Some('n')) | ('\\', Some('N')) => {
+                    ret.push('\n');
+                    chars.next();
+                }
+                ('\\', Some(escaped)) => {
+                    ret.push(escaped);
+                    chars.next();
+                }
+                (c, _) => ret.push(c),
+            }
+        }
+        Some(ret.trim().to_string()).filter(|s| !s.is_empty())
+    }
+}
+
+/// Join folded lines, which continue on the next line after a space or tab
+fn unfold(body: &str) -> Vec<String> {
+    let mut ret: Vec<String> = Vec::new();
+    for line in body.lines() {
+        match (line.strip_prefix(|c| c == ' ' || c == '\t'), ret.last_mut()) {
+            (Some(rest), Some(last)) => last.push_str(rest),
+            _ => ret.push(line.to_string()),
+        }
+    }
+    ret
+}
+
+/// Map one VEVENT onto our columns, or None if it was cancelled
+fn ical_event(
+    properties: &[Property],
+    page: &Page,
+    source: &EventSource,
+    fields: &FieldMap,
+) -> AppResult<Option<NewEvent>> {
+    let field = |mapped: &Option<String>, default: &str| {
+        let name = mapped.as_deref().unwrap_or(default).to_uppercase();
+        properties.iter().find(|p| p.name == name)
+    };
+    let title = field(&fields.title, "SUMMARY")
+        .and_then(Property::text)
+        .ok_or_else(|| AppError::parse("VEVENT has no title"))?;
+    if field(&None, "STATUS").is_some_and(|p| p.value.eq_ignore_ascii_case("CANCELLED")) {
+        debug!("{}: skipping cancelled {:?}", source.as_str(), title);
+        return Ok(None);
+    }
+    let start = field(&fields.start, "DTSTART")
+        .ok_or_else(|| AppError::parse(format!("VEVENT {:?} has no start", title)))?;
+    let event_date = ical_date(&start.value)?;
+    let event_end_date = match field(&fields.end, "DTEND") {
+        // DTEND is exclusive, so an all-day event ends the day before
+        Some(end) if end.name == "DTEND" && end.value.len() == 8 => {
+            let day = NaiveDate::parse_from_str(&end.value, "%Y%m%d")
+                .map_err(|e| AppError::parse(format!("bad date {:?}: {}", end.value, e)))?;
+            Some(day.pred().to_string()).filter(|end| *end != event_date)
+        }
+        Some(end) => Some(ical_date(&end.value)?),
+        None => None,
+    };
+    let href = match field(&fields.href, "URL").and_then(Property::text) {
+        Some(url) => page.resolve(&url)?,
+        None => page.url.to_string(),
+    };
+    // LOCATION names the venue, so there's only a subtitle if one is mapped
+    let subtitle = match fields.subtitle {
+        Some(_) => field(&fields.subtitle, "").and_then(Property::text),
+        None => None,
+    };
+    let synopsis = field(&fields.synopsis, "DESCRIPTION")
+        .and_then(Property::text)
+        .unwrap_or_default();
+
+    Ok(Some(NewEvent {
+        venue: field(&None, "LOCATION").and_then(Property::text),
+        ..NewEvent::new(
+            &title,
+            subtitle,
+            &href,
+            &synopsis,
+            &event_date,
+            event_end_date,
+            source.clone(),
+        )
+    }))
+}
+
+/// Stored form of an iCal DATE or DATE-TIME, in Berlin time
+/// UTC times are converted to Berlin time, whatever the server's timezone.
+fn ical_date(value: &str) -> AppResult<String> {
+    let value = value.trim();
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return Ok(date.to_string());
+    }
+    if let Some(utc) = value.strip_suffix('Z') {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S") {
+            return Ok(Berlin.from_utc_datetime(&dt).naive_local().to_string());
+        }
+    }
+    match NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S") {
+        Ok(dt) => Ok(dt.to_string()),
+        // A remapped field may hold something other than an iCal date
+        Err(_) => normalize_date(value),
+    }
+}
+
+/// Map one RSS item or Atom entry onto our columns
+fn feed_item(
+    item: Node,
+    page: &Page,
+    source: &EventSource,
+    fields: &FieldMap,
+) -> AppResult<NewEvent> {
+    let atom = item.tag_name().name() == "entry";
+    let field = |mapped: &Option<String>, rss: &[&str], atom_defaults: &[&str]| match mapped {
+        Some(name) => child_text(item, name),
+        None if atom => atom_defaults.iter().find_map(|name| child_text(item, name)),
+        None => rss.iter().find_map(|name| child_text(item, name)),
+    };
+    let title = field(&fields.title, &["title"], &["title"])
+        .ok_or_else(|| AppError::parse("feed item has no title"))?;
+    // A feed's own dates say when an item was published, which is only a stand-in
+    let start = field(
+        &fields.start,
+        &["pubDate", "date"],
+        &["published", "updated"],
+    )
+    .ok_or_else(|| {
+        AppError::parse(format!(
+            "feed item {:?} has no date - map one with fields.start",
+            title
+        ))
+    })?;
+    let event_date = feed_date(&start)?;
+    let event_end_date = match field(&fields.end, &[], &[]) {
+        Some(end) => Some(feed_date(&end)?),
+        None => None,
+    };
+    let href = match field(&fields.href, &["link"], &["link"]) {
+        Some(url) => page.resolve(&url)?,
+        None => page.url.to_string(),
+    };
+    let subtitle = field(&fields.subtitle, &[], &[]);
+    // Descriptions are usually escaped HTML
+    let synopsis = field(&fields.synopsis, &["description"], &["summary", "content"])
+        .map(|s| strip_html(&s))
+        .unwrap_or_default();
+
+    Ok(NewEvent::new(
+        &title,
+        subtitle,
+        &href,
+        &synopsis,
+        &event_date,
+        event_end_date,
+        source.clone(),
+    ))
+}
+
+/// Trimmed text of the first child element called `name`, ignoring any namespace prefix
+/// Atom links keep their URL in an attribute, and only an `alternate` one is the event's page.
+fn child_text(item: Node, name: &str) -> Option<String> {
+    let local = name.rsplit(':').next().unwrap_or(name);
+    item.children()
+        .filter(|child| child.is_element() && child.tag_name().name() == local)
+        .find_map(|child| match child.attribute("href") {
+            Some(href) => Some(href.to_string())
+                .filter(|_| child.attribute("rel").is_none_or(|rel| rel == "alternate")),
+            None => Some(
+                child
+                    .descendants()
+                    .filter_map(|n| n.text().filter(|_| n.is_text()))
+                    .collect(),
+            ),
+        })
+        .map(|text: String| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+/// Stored form of an RSS (RFC 2822) or Atom (RFC 3339) date, in Berlin time
+fn feed_date(s: &str) -> AppResult<String> {
+    match DateTime::parse_from_rfc2822(s) {
+        Ok(dt) => Ok(dt.with_timezone(&Berlin).naive_local().to_string()),
+        Err(_) => normalize_date(s),
+    }
+}
+
+/// Text content of an HTML fragment
+fn strip_html(html: &str) -> String {
+    Document::from(html)
+        .find(Name("body"))
+        .next()
+        .map(|body| body.text().trim().to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_ical() {
+        let ics = "BEGIN:VCALENDAR\r
+VERSION:2.0\r
+BEGIN:VEVENT\r
+SUMMARY:Late Concert\\, Strings\r
+DTSTART;TZID=Europe/Berlin:20200306T210000\r
+URL:/en/late-concert\r
+LOCATION:Hall A\r
+DESCRIPTION:Three quartets\\nand an enco\r
+ re\r
+BEGIN:VALARM\r
+DESCRIPTION:Reminder\r
+END:VALARM\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+SUMMARY:Open Day\r
+DTSTART;VALUE=DATE:20200307\r
+DTEND;VALUE=DATE:20200309\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+SUMMARY:Called Off\r
+STATUS:CANCELLED\r
+DTSTART;VALUE=DATE:20200310\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+SUMMARY:Date to be announced\r
+END:VEVENT\r
+BEGIN:VEVENT\r
+SUMMARY:Summer Screening\r
+DTSTART:20200706T190000Z\r
+END:VEVENT\r
+END:VCALENDAR\r
+";
+        let page = Page::new("https://venue.example/events.ics".parse().unwrap(), ics);
+        let source = EventSource::Ical("Venue".into(), true);
+        let parsed = parse_ical_events(&page, &source, &FieldMap::default()).unwrap();
+
+        assert_eq!(parsed.failed, 1);
+        assert_eq!(
+            parsed.events,
+            vec![
+                NewEvent {
+                    venue: Some("Hall A".into()),
+                    ..NewEvent::new(
+                        "Late Concert, Strings",
+                        None,
+                        "https://venue.example/en/late-concert",
+                        "Three quartets\nand an encore",
+                        "2020-03-06 21:00:00",
+                        None,
+                        source.clone(),
+                    )
+                },
+                NewEvent::new(
+                    "Open Day",
+                    None,
+                    "https://venue.example/events.ics",
+                    "",
+                    "2020-03-07",
+                    Some("2020-03-08".into()),
+                    source.clone(),
+                ),
+                NewEvent::new(
+                    "Summer Screening",
+                    None,
+                    "https://venue.example/events.ics",
+                    "",
+                    "2020-07-06 21:00:00",
+                    None,
+                    source.clone(),
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ical_date_utc() {
+        assert_eq!(
+            ical_date("20200306T200000Z").unwrap(),
+            "2020-03-06 21:00:00"
+        );
+        assert_eq!(
+            ical_date("20200706T190000Z").unwrap(),
+            "2020-07-06 21:00:00"
+        );
+    }
+
+    #[test]
+    fn test_feed_date() {
+        assert_eq!(
+            feed_date("Fri, 06 Mar 2020 19:00:00 GMT").unwrap(),
+            "2020-03-06 20:00:00"
+        );
+        assert_eq!(
+            feed_date("Mon, 06 Jul 2020 19:00:00 +0000").unwrap(),
+            "2020-07-06 21:00:00"
+        );
+        assert_eq!(
+            feed_date("Mon, 02 Mar 2020 09:00:00 +0100").unwrap(),
+            "2020-03-02 09:00:00"
+        );
+    }
+
+    #[test]
+    fn test_parse_rss() {
+        let rss = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:ev="http://purl.org/rss/1.0/modules/event/">
+<channel>
+  <title>Venue</title>
+  <item>
+    <title>Poetry Night</title>
+    <link>https://venue.example/poetry</link>
+    <description>&lt;p&gt;Readings &amp;amp; wine&lt;/p&gt;</description>
+    <pubDate>Mon, 02 Mar 2020 09:00:00 +0100</pubDate>
+    <ev:startdate>2020-03-12T19:30:00+01:00</ev:startdate>
+    <ev:location>Studio</ev:location>
+  </item>
+  <item>
+    <title>Date to be announced</title>
+  </item>
+</channel>
+</rss>"#;
+        let page = Page::new("https://venue.example/feed".parse().unwrap(), rss);
+        let source = EventSource::Rss("Venue".into(), true);
+        let fields = FieldMap {
+            start: Some("ev:startdate".into()),
+            subtitle: Some("ev:location".into()),
+            ..FieldMap::default()
+        };
+        let parsed = parse_rss_events(&page, &source, &fields).unwrap();
+        assert_eq!(parsed.failed, 1);
+        assert_eq!(
+            parsed.events,
+            vec![NewEvent::new(
+                "Poetry Night",
+                Some("Studio".into()),
+                "https://venue.example/poetry",
+                "Readings & wine",
+                "2020-03-12 19:30:00",
+                None,
+                source.clone(),
+            )]
+        );
+
+        let atom = r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Venue</title>
+  <entry>
+    <title>Film Club</title>
+    <link rel="enclosure" href="/poster.jpg"/>
+    <link href="/film-club"/>
+    <updated>2020-03-14T20:00:00+01:00</updated>
+    <summary>Double feature</summary>
+  </entry>
+</feed>"#;
+        let page = Page::new("https://venue.example/atom.xml".parse().unwrap(), atom);
+        let parsed = parse_rss_events(&page, &source, &FieldMap::default()).unwrap();
+        assert_eq!(parsed.failed, 0);
+        assert_eq!(
+            parsed.events,
+            vec![NewEvent::new(
+                "Film Club",
+                None,
+                "https://venue.example/film-club",
+                "Double feature",
+                "2020-03-14 20:00:00",
+                None,
+                source.clone(),
+            )]
+        );
+    }
+}
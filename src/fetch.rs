@@ -0,0 +1,402 @@
+// fetch.rs
+// Where source pages come from - the network or saved copies - and archiving what was fetched
+
+use super::*;
+use chrono::prelude::*;
+use hyper::{header, HeaderMap, StatusCode};
+use log::{debug, warn};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokio::time::{sleep_until, Duration, Instant};
+use url::Url;
+
+/// How long a site's robots.txt is trusted before it's fetched again
+const ROBOTS_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Times a request is retried after being told to back off with Retry-After
+const MAX_RETRIES: usize = 2;
+
+/// Origin of the pages a `Fetcher` returns
+#[derive(Debug)]
+enum Origin {
+    /// Live pages over HTTP
+    Network(Network),
+    /// A single saved page, or a directory of them
+    Local(PathBuf),
+}
+
+/// Retrieves source pages, optionally archiving each one fetched from the network
+/// A directory of saved pages may hold `<Source>.html`, or a `<Source>/` directory of
+/// snapshots in the layout the archive writes, in which case the newest is used.
+#[derive(Debug)]
+pub struct Fetcher {
+    origin: Origin,
+    archive_dir: Option<PathBuf>,
+}
+
+impl Fetcher {
+    /// Fetcher for the configured origin and archive
+    pub fn new(opt: &Opt) -> AppResult<Self> {
+        let origin = match &opt.pages_from {
+            Some(path) => Origin::Local(path.clone()),
+            None => Origin::Network(Network::new(opt)?),
+        };
+        Ok(Self {
+            origin,
+            archive_dir: opt.archive_dir.clone(),
+        })
+    }
+    /// Fetcher reading saved pages from a file or directory, archiving nothing
+    pub fn local(path: impl Into<PathBuf>) -> Self {
+        Self {
+            origin: Origin::Local(path.into()),
+            archive_dir: None,
+        }
+    }
+    /// Retrieve the current HTML of a source's page
+    pub async fn fetch(&self, source: &EventSource, url: &str) -> AppResult<Page> {
+        match self.download(source, url, None).await? {
+            Download::Page { html, url, .. } => Ok(Page::new(url, &html)),
+            Download::NotModified => Err(AppError::Upstream(anyhow::anyhow!(
+                "{} answered 304 to an unconditional request",
+                url
+            ))),
+        }
+    }
+    /// Retrieve a source's page unless it's the same as when `previous` was stored
+    pub async fn fetch_if_changed(
+        &self,
+        source: &EventSource,
+        url: &str,
+        previous: Option<&SourcePage>,
+    ) -> AppResult<Fetched> {
+        let fetched_dt = Utc::now().to_rfc3339();
+        match (self.download(source, url, previous).await?, previous) {
+            (Download::NotModified, Some(previous)) => Ok(Fetched::Unchanged(SourcePage {
+                fetched_dt,
+                ..previous.clone()
+            })),
+            (Download::NotModified, None) => Err(AppError::Upstream(anyhow::anyhow!(
+                "{} answered 304 to an unconditional request",
+                url
+            ))),
+            (
+                Download::Page {
+                    html,
+                    url: final_url,
+                    etag,
+                    last_modified,
+                },
+                previous,
+            ) => {
+                let source_page = SourcePage {
+                    url: url.into(),
+                    etag,
+                    last_modified,
+                    content_hash: content_hash(html.as_bytes()),
+                    fetched_dt,
+                };
+                if previous.is_some_and(|p| p.content_hash == source_page.content_hash) {
+                    Ok(Fetched::Unchanged(source_page))
+                } else {
+                    Ok(Fetched::Changed(Page::new(final_url, &html), source_page))
+                }
+            }
+        }
+    }
+    /// Retrieve a page from wherever this fetcher reads, archiving it if it was downloaded
+    async fn download(
+        &self,
+        source: &EventSource,
+        url: &str,
+        previous: Option<&SourcePage>,
+    ) -> AppResult<Download> {
+        match &self.origin {
+            Origin::Network(network) => {
+                let download = network.get(url, previous).await?;
+                if let (Some(dir), Download::Page { html, .. }) = (&self.archive_dir, &download) {
+                    archive_page(dir, source, html)?;
+                }
+                Ok(download)
+            }
+            // Saved pages don't know where they came from, so links resolve against `url`
+            Origin::Local(path) => Ok(Download::Page {
+                html: fs::read_to_string(saved_page(path, source)?)?,
+                url: parse_url(url)?,
+                etag: None,
+                last_modified: None,
+            }),
+        }
+    }
+}
+
+/// Result of a conditional fetch
+#[derive(Debug)]
+pub enum Fetched {
+    /// New content, and the validators to store for next time
+    Changed(Page, SourcePage),
+    /// Same as the last fetch, going by a 304 or an identical content hash
+    Unchanged(SourcePage),
+}
+
+/// A page as retrieved, before comparing it with the last fetch
+#[derive(Debug)]
+enum Download {
+    NotModified,
+    Page {
+        html: String,
+        /// Where the page was finally served from, after any redirects
+        url: Url,
+        etag: Option<String>,
+        last_modified: Option<String>,
+    },
+}
+
+/// What we remember about each host we fetch from
+#[derive(Debug)]
+struct HostState {
+    /// Earliest time the next request may be sent
+    next_request: Instant,
+    /// Parsed robots.txt and when it was fetched
+    robots: Option<(Robots, Instant)>,
+}
+
+/// HTTP client that identifies itself, spaces out requests to each host, obeys
+/// robots.txt and backs off when told to with Retry-After
+#[derive(Debug)]
+struct Network {
+    client: reqwest::Client,
+    user_agent: String,
+    delay: Duration,
+    max_retry_wait: Duration,
+    /// Keyed by origin, e.g. `http://berghain.de`
+    hosts: Mutex<HashMap<String, HostState>>,
+}
+
+impl Network {
+    fn new(opt: &Opt) -> AppResult<Self> {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .user_agent(opt.user_agent.as_str())
+                .build()?,
+            user_agent: opt.user_agent.clone(),
+            delay: Duration::from_millis(opt.fetch_delay_ms),
+            max_retry_wait: Duration::from_secs(opt.max_retry_wait),
+            hosts: Mutex::new(HashMap::new()),
+        })
+    }
+    /// GET a page if robots.txt allows it, conditional on the validators in `previous`
+    async fn get(&self, url: &str, previous: Option<&SourcePage>) -> AppResult<Download> {
+        let parsed = parse_url(url)?;
+        let origin = parsed.origin().ascii_serialization();
+        let path = match parsed.query() {
+            Some(query) => format!("{}?{}", parsed.path(), query),
+            None => parsed.path().to_string(),
+        };
+        if !self.robots(&origin).await?.allowed(&path) {
+            return Err(AppError::Upstream(anyhow::anyhow!(
+                "robots.txt disallows {}",
+                url
+            )));
+        }
+        let mut headers = HeaderMap::new();
+        if let Some(previous) = previous {
+            if let Some(etag) = &previous.etag {
+                headers.insert(header::IF_NONE_MATCH, etag.parse()?);
+            }
+            if let Some(last_modified) = &previous.last_modified {
+                headers.insert(header::IF_MODIFIED_SINCE, last_modified.parse()?);
+            }
+        }
+        let response = self.send(&origin, url, &headers).await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(Download::NotModified);
+        }
+        let response = response.error_for_status()?;
+        let validator = |name| {
+            response
+                .headers()
+                .get(name)
+                .and_then(|v| v.to_str().ok())
+                .map(String::from)
+        };
+        let etag = validator(header::ETAG);
+        let last_modified = validator(header::LAST_MODIFIED);
+        let final_url = response.url().clone();
+        Ok(Download::Page {
+            html: response.text().await?,
+            url: final_url,
+            etag,
+            last_modified,
+        })
+    }
+    /// Send a request once it's this host's turn, retrying when asked to back off
+    async fn send(
+        &self,
+        origin: &str,
+        url: &str,
+        headers: &HeaderMap,
+    ) -> AppResult<reqwest::Response> {
+        let mut retries = 0;
+        loop {
+            self.wait_turn(origin).await;
+            let response = self.client.get(url).headers(headers.clone()).send().await?;
+            let status = response.status();
+            if status != StatusCode::TOO_MANY_REQUESTS && status != StatusCode::SERVICE_UNAVAILABLE
+            {
+                return Ok(response);
+            }
+            // Whether or not we retry, nothing more goes to this host until it's ready
+            let wait = retry_after(response.headers());
+            if let Some(wait) = wait {
+                self.defer(origin, wait);
+            }
+            match wait {
+                Some(wait) if retries < MAX_RETRIES && wait <= self.max_retry_wait => {
+                    warn!("{} answered {}, retrying in {:?}", url, status, wait);
+                    retries += 1;
+                }
+                _ => return Ok(response),
+            }
+        }
+    }
+    /// Wait until the next request to `origin` is due, then book the one after it
+    async fn wait_turn(&self, origin: &str) {
+        let start = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let host = hosts.entry(origin.into()).or_insert_with(|| HostState {
+                next_request: Instant::now(),
+                robots: None,
+            });
+            let start = host.next_request.max(Instant::now());
+            host.next_request = start + self.delay;
+            start
+        };
+        sleep_until(start).await;
+    }
+    /// Hold off every request to `origin` for at least `wait`
+    fn defer(&self, origin: &str, wait: Duration) {
+        let mut hosts = self.hosts.lock().unwrap();
+        if let Some(host) = hosts.get_mut(origin) {
+            host.next_request = host.next_request.max(Instant::now() + wait);
+        }
+    }
+    /// The robots.txt rules for `origin`, fetching them if we don't have a fresh copy
+    /// A missing robots.txt allows everything, and a server error disallows everything
+    /// until it can be fetched again.
+    async fn robots(&self, origin: &str) -> AppResult<Robots> {
+        let cached = self
+            .hosts
+            .lock()
+            .unwrap()
+            .get(origin)
+            .and_then(|host| host.robots.clone())
+            .filter(|(_, fetched)| fetched.elapsed() < ROBOTS_TTL);
+        if let Some((robots, _)) = cached {
+            return Ok(robots);
+        }
+
+        let response = self
+            .send(origin, &format!("{}/robots.txt", origin), &HeaderMap::new())
+            .await?;
+        let status = response.status();
+        let robots = if status.is_success() {
+            Robots::parse(&response.text().await?, &self.user_agent)
+        } else if status.is_client_error() {
+            Robots::allow_all()
+        } else {
+            warn!(
+                "{}/robots.txt answered {}, fetching nothing",
+                origin, status
+            );
+            return Ok(Robots::disallow_all());
+        };
+        if let Some(host) = self.hosts.lock().unwrap().get_mut(origin) {
+            host.robots = Some((robots.clone(), Instant::now()));
+        }
+        Ok(robots)
+    }
+}
+
+/// Parse a configured source URL
+fn parse_url(url: &str) -> AppResult<Url> {
+    Url::parse(url).map_err(|e| AppError::Validation(format!("bad source URL {:?}: {}", url, e)))
+}
+
+/// Delay requested by a Retry-After header, given in seconds or as an HTTP-date
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(header::RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    let secs = (date.with_timezone(&Utc) - Utc::now()).num_seconds().max(0);
+    Some(Duration::from_secs(secs as u64))
+}
+
+/// Write a fetched page to `<dir>/<Source>/<UTC timestamp>.html`
+fn archive_page(dir: &Path, source: &EventSource, html: &str) -> AppResult<PathBuf> {
+    let dir = dir.join(source.as_str());
+    fs::create_dir_all(&dir)?;
+    let path = dir.join(format!("{}.html", Utc::now().format("%Y%m%dT%H%M%S%.3fZ")));
+    fs::write(&path, html)?;
+    debug!("Archived {} page to {}", source.as_str(), path.display());
+    Ok(path)
+}
+
+/// Locate the saved page for a source under `path`
+fn saved_page(path: &Path, source: &EventSource) -> AppResult<PathBuf> {
+    if path.is_file() {
+        return Ok(path.to_path_buf());
+    }
+    let snapshots = path.join(source.as_str());
+    if snapshots.is_dir() {
+        // Archive timestamps sort in time order
+        let newest = fs::read_dir(&snapshots)?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|p| p.extension().is_some_and(|ext| ext == "html"))
+            .max();
+        if let Some(newest) = newest {
+            return Ok(newest);
+        }
+    }
+    let page = path.join(format!("{}.html", source.as_str()));
+    if page.is_file() {
+        return Ok(page);
+    }
+    Err(AppError::Upstream(anyhow::anyhow!(
+        "no saved {} page in {}",
+        source.as_str(),
+        path.display()
+    )))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_archive_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = EventSource::Berghain(true);
+        assert!(saved_page(dir.path(), &source).is_err());
+
+        fs::write(dir.path().join("Berghain.html"), "flat").unwrap();
+        assert_eq!(
+            saved_page(dir.path(), &source).unwrap(),
+            dir.path().join("Berghain.html")
+        );
+
+        // Snapshots win over a flat page, newest first
+        archive_page(dir.path(), &source, "older").unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let newest = archive_page(dir.path(), &source, "newer").unwrap();
+        assert_eq!(saved_page(dir.path(), &source).unwrap(), newest);
+        assert_eq!(fs::read_to_string(newest).unwrap(), "newer");
+    }
+}
@@ -0,0 +1,208 @@
+// files.rs
+// Static files served from a directory on disk
+
+use super::*;
+use chrono::prelude::*;
+use hyper::{header, Body, HeaderMap, Request, Response, StatusCode};
+use percent_encoding::percent_decode_str;
+use std::{
+    io::SeekFrom,
+    path::{Component, Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+/// URL prefix the static directory is mounted under
+pub const STATIC_PREFIX: &str = "/images/";
+/// Files on disk can change between deploys, so they're only cached for a day
+const FILE_CACHE: &str = "public, max-age=86400";
+
+/// The portion of a file a request asked for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ByteRange {
+    /// No usable Range header - send everything
+    Full,
+    /// Inclusive first and last byte offsets
+    Partial(u64, u64),
+    /// The range lies entirely outside the file
+    Unsatisfiable,
+}
+
+impl ByteRange {
+    /// Parse a `Range` header value against a file length
+    /// Only single `bytes` ranges are honoured; anything else falls back to the full file.
+    pub fn parse(value: &str, len: u64) -> Self {
+        let spec = match value.trim().strip_prefix("bytes=") {
+            Some(spec) if !spec.contains(',') => spec.trim(),
+            _ => return ByteRange::Full,
+        };
+        let (start, end) = match spec.split_once('-') {
+            Some(pair) => pair,
+            None => return ByteRange::Full,
+        };
+        let range = match (start.parse::<u64>(), end.parse::<u64>()) {
+            // bytes=a-b
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+            // bytes=a-
+            (Ok(start), Err(_)) if end.is_empty() => (start, len.saturating_sub(1)),
+            // bytes=-n, the final n bytes
+            (Err(_), Ok(suffix)) if start.is_empty() => {
+                if suffix == 0 {
+                    return ByteRange::Unsatisfiable;
+                }
+                (len.saturating_sub(suffix), len.saturating_sub(1))
+            }
+            _ => return ByteRange::Full,
+        };
+        if len == 0 || range.0 >= len {
+            ByteRange::Unsatisfiable
+        } else {
+            ByteRange::Partial(range.0, range.1)
+        }
+    }
+    /// Work out the range to serve, ignoring Range when If-Range doesn't match the ETag
+    fn from_headers(headers: &HeaderMap, etag: &str, len: u64) -> Self {
+        if let Some(if_range) = headers.get(header::IF_RANGE) {
+            if if_range.to_str().map_or(true, |v| v.trim() != etag) {
+                return ByteRange::Full;
+            }
+        }
+        match headers.get(header::RANGE).and_then(|h| h.to_str().ok()) {
+            Some(value) => Self::parse(value, len),
+            None => ByteRange::Full,
+        }
+    }
+}
+
+/// Map a percent-encoded request path onto a file below `root`
+/// Returns None for anything that could escape the directory or names a hidden file.
+pub fn resolve_static_path(root: &Path, request_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(request_path).decode_utf8().ok()?;
+    let mut path = root.to_path_buf();
+    for component in Path::new(decoded.as_ref()).components() {
+        match component {
+            Component::Normal(part) if !part.to_string_lossy().starts_with('.') => path.push(part),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
+/// Serve a file from the static directory, honouring conditional and range requests
+pub async fn static_file(req: &Request<Body>, root: &Path, request_path: &str) -> HandlerResult {
+    let path = match resolve_static_path(root, request_path) {
+        Some(path) => path,
+        None => return four_oh_four().await,
+    };
+    // Symlinks must not lead outside the directory either
+    let path = match (fs::canonicalize(root).await, fs::canonicalize(&path).await) {
+        (Ok(root), Ok(path)) if path.starts_with(&root) => path,
+        _ => return four_oh_four().await,
+    };
+    let meta = fs::metadata(&path).await?;
+    if !meta.is_file() {
+        return four_oh_four().await;
+    }
+
+    // Validators
+    let len = meta.len();
+    let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?.as_secs();
+    let etag = format!("\"{:x}-{:x}\"", mtime, len);
+    let last_modified = format_http_date(Utc.timestamp_opt(mtime as i64, 0).unwrap());
+    let headers = req.headers();
+    if etag_matches(headers, &etag) || unmodified_since(headers, &last_modified) {
+        return not_modified(&etag, FILE_CACHE);
+    }
+
+    let response = Response::builder()
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, &last_modified)
+        .header(header::CACHE_CONTROL, FILE_CACHE);
+    let (status, start, end) = match ByteRange::from_headers(headers, &etag, len) {
+        ByteRange::Full => (StatusCode::OK, 0, len.saturating_sub(1)),
+        ByteRange::Partial(start, end) => (StatusCode::PARTIAL_CONTENT, start, end),
+        ByteRange::Unsatisfiable => {
+            return Ok(response
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{}", len))
+                .body(Body::empty())?)
+        }
+    };
+
+    // Read just the requested bytes
+    let mut buf = vec![
+        0;
+        if len == 0 {
+            0
+        } else {
+            (end - start + 1) as usize
+        }
+    ];
+    let mut file = fs::File::open(&path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    file.read_exact(&mut buf).await?;
+
+    let mut response = response.status(status).header(
+        header::CONTENT_TYPE,
+        mime_guess::from_path(&path)
+            .first_or_octet_stream()
+            .essence_str(),
+    );
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(
+            header::CONTENT_RANGE,
+            format!("bytes {}-{}/{}", start, end, len),
+        );
+    }
+    Ok(response.body(Body::from(buf))?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(
+            ByteRange::parse("bytes=0-99", 1000),
+            ByteRange::Partial(0, 99)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=900-", 1000),
+            ByteRange::Partial(900, 999)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=-100", 1000),
+            ByteRange::Partial(900, 999)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=500-5000", 1000),
+            ByteRange::Partial(500, 999)
+        );
+        assert_eq!(
+            ByteRange::parse("bytes=1000-", 1000),
+            ByteRange::Unsatisfiable
+        );
+        assert_eq!(ByteRange::parse("bytes=0-1,5-6", 1000), ByteRange::Full);
+        assert_eq!(ByteRange::parse("lines=1-2", 1000), ByteRange::Full);
+    }
+
+    #[test]
+    fn test_resolve_static_path() {
+        let root = Path::new("images");
+        assert_eq!(
+            resolve_static_path(root, "venues/berghain%20logo.svg"),
+            Some(PathBuf::from("images/venues/berghain logo.svg"))
+        );
+        assert_eq!(resolve_static_path(root, "../Cargo.toml"), None);
+        assert_eq!(resolve_static_path(root, "%2e%2e/Cargo.toml"), None);
+        assert_eq!(resolve_static_path(root, "/etc/passwd"), None);
+        assert_eq!(resolve_static_path(root, ".git/config"), None);
+    }
+}
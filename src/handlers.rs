@@ -4,9 +4,12 @@
 use super::*;
 use askama::Template;
 use chrono::prelude::*;
-use flate2::{write::ZlibEncoder, Compression};
-use hyper::{header, Body, Request, Response, StatusCode};
-use std::{collections::HashMap, convert::TryInto, fs::File, io::prelude::*, path::PathBuf};
+use diesel::sqlite::SqliteConnection;
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use hyper::{body::Bytes, header, http, Body, HeaderMap, Method, Request, Response, StatusCode};
+use log::info;
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, convert::TryInto, io::prelude::*};
 use url::form_urlencoded;
 
 // Universal handler return type
@@ -14,23 +17,132 @@ pub type HandlerResult = AppResult<Response<Body>>;
 
 // General handlers
 
-/// Top-level handler that DEFLATE compresses and responds from a &[u8] body
-/// If None passed to status, 200 OK will be returned
+/// DEFLATE compress a body
+pub fn deflate(body: &[u8]) -> AppResult<Vec<u8>> {
+    let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+    e.write_all(body)?;
+    Ok(e.finish()?)
+}
+
+/// Undo `deflate`
+pub fn inflate(body: &[u8]) -> AppResult<Vec<u8>> {
+    let mut ret = Vec::new();
+    ZlibDecoder::new(body).read_to_end(&mut ret)?;
+    Ok(ret)
+}
+
+/// Check whether the request's Accept-Encoding allows a DEFLATE compressed body
+/// A `deflate` entry wins over `*`, and either is refused with `q=0`.
+pub fn accepts_deflate(headers: &HeaderMap) -> bool {
+    let mut deflate = None;
+    let mut any = None;
+    for entry in headers
+        .get_all(header::ACCEPT_ENCODING)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+    {
+        let mut parts = entry.split(';');
+        let coding = parts.next().unwrap_or_default().trim();
+        // Without a q it's fully accepted, and a malformed one is taken as a refusal
+        let q = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .map_or(1.0, |q| q.trim().parse::<f32>().unwrap_or(0.0));
+        if coding.eq_ignore_ascii_case("deflate") {
+            deflate = Some(q);
+        } else if coding == "*" {
+            any = Some(q);
+        }
+    }
+    deflate.or(any).is_some_and(|q| q > 0.0)
+}
+
+/// Finish a response whose body is stored compressed, inflating it for clients that
+/// don't accept deflate
+pub fn deflated_response(
+    response: http::response::Builder,
+    headers: &HeaderMap,
+    compressed: Bytes,
+) -> HandlerResult {
+    let response = response.header(header::VARY, "Accept-Encoding");
+    if accepts_deflate(headers) {
+        Ok(response
+            .header(header::CONTENT_ENCODING, "deflate")
+            .body(Body::from(compressed))?)
+    } else {
+        Ok(response.body(Body::from(inflate(&compressed)?))?)
+    }
+}
+
+/// Hex SHA-256 of a body, the same on every build
+pub fn content_hash(body: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(body))
+}
+
+/// Check whether the request's If-None-Match header matches the given ETag
+pub fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    match headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+    {
+        Some(value) => value.split(',').map(str::trim).any(|candidate| {
+            candidate == "*" || candidate.trim_start_matches("W/") == etag.trim_start_matches("W/")
+        }),
+        None => false,
+    }
+}
+
+/// Check whether the request's If-Modified-Since is at or after the given HTTP-date
+/// Only consulted when the request carries no If-None-Match
+pub fn unmodified_since(headers: &HeaderMap, last_modified: &str) -> bool {
+    if headers.contains_key(header::IF_NONE_MATCH) {
+        return false;
+    }
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| DateTime::parse_from_rfc2822(h).ok());
+    match (since, DateTime::parse_from_rfc2822(last_modified)) {
+        (Some(since), Ok(modified)) => modified <= since,
+        _ => false,
+    }
+}
+
+/// Format a timestamp as an HTTP-date
+pub fn format_http_date(dt: DateTime<Utc>) -> String {
+    dt.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+/// Format an RFC 3339 timestamp as an HTTP-date
+pub fn http_date(rfc3339: &str) -> AppResult<String> {
+    Ok(format_http_date(
+        DateTime::parse_from_rfc3339(rfc3339)?.with_timezone(&Utc),
+    ))
+}
+
+/// Empty 304 response echoing the validators
+/// Only used for pages whose encoding is negotiated, so it also says what it varies by.
+pub fn not_modified(etag: &str, cache_control: &str) -> HandlerResult {
+    Ok(Response::builder()
+        .status(StatusCode::NOT_MODIFIED)
+        .header(header::ETAG, etag)
+        .header(header::CACHE_CONTROL, cache_control)
+        .header(header::VARY, "Accept-Encoding")
+        .body(Body::empty())?)
+}
+
+/// Top-level handler that responds from a &[u8] body
+/// If None passed to status, 200 OK will be returned. The `compress` middleware
+/// takes care of compression.
 pub async fn bytes_handler(
     body: &[u8],
     content_type: &str,
     status: Option<StatusCode>,
 ) -> HandlerResult {
-    // Compress
-    let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
-    e.write_all(body)?;
-    let compressed = e.finish()?;
-    // Return response
     Ok(Response::builder()
         .status(status.unwrap_or_default())
         .header(header::CONTENT_TYPE, content_type)
-        .header(header::CONTENT_ENCODING, "deflate")
-        .body(Body::from(compressed))?)
+        .body(Body::from(body.to_vec()))?)
 }
 
 /// Pass string to bytes_handler
@@ -43,144 +155,287 @@ pub async fn string_handler(
 }
 
 /// Pass HTML string to string_handler
-pub async fn html_str_handler(body: &str) -> HandlerResult {
-    string_handler(body, "text/html", None).await
+pub async fn html_str_handler(body: &str, status: Option<StatusCode>) -> HandlerResult {
+    string_handler(body, "text/html", status).await
 }
 
 // Route handlers
 
-/// Serve any image assets requested
-pub async fn image(path_str: &str) -> HandlerResult {
-    let path_buf = PathBuf::from(path_str);
-    let file_name = path_buf.file_name().unwrap().to_str().unwrap();
-    if let Some(ext) = path_buf.extension() {
-        match ext.to_str().unwrap() {
-            "ico" => {
-                let mut file = File::open("images/favicon.ico")?;
-                let mut buf = Vec::new();
-                file.read_to_end(&mut buf)?;
-                bytes_handler(&buf, "image/x-icon", None).await
-            }
-            "svg" => {
-                // build the response
-                let xml = match file_name {
-                    // "dev-badge.svg" => include_str!("assets/images/dev-badge.svg"), // for example
-                    _ => "",
-                };
-                string_handler(xml, "image/svg+xml", None).await
-            }
-            _ => four_oh_four().await,
-        }
-    } else {
-        four_oh_four().await
-    }
-}
-
 /// Serve main page
-pub async fn index(req: Request<Body>) -> HandlerResult {
+/// Rendered pages are cached per filter until the events change, and GETs are answered
+/// with 304 when the client's validators match the page as it renders now.
+pub async fn index(req: Request<Body>, state: &AppState) -> HandlerResult {
+    let (parts, body) = req.into_parts();
+
     // Parse params, if any
-    let params = form_urlencoded::parse(hyper::body::to_bytes(req).await?.as_ref())
+    let params = form_urlencoded::parse(hyper::body::to_bytes(body).await?.as_ref())
         .into_owned()
         .collect::<HashMap<String, String>>();
+    let query = ListingQuery::from_params(&params, &state.sources)?;
 
-    // Parse sources
-    let mut sources = {
-        let mut ret = Vec::new();
-        let all_possible = EventSource::all();
-        for source in all_possible {
-            if params.get(&source.markup_name()).is_some() {
-                ret.push(*source);
-            } else {
-                ret.push(source.toggle());
-            }
+    let cache = &state.listing_cache;
+    let version = state
+        .metrics
+        .timed("data_version", || data_version(&*state.pool.get()?))?;
+    cache.sync(version);
+    let (page, cache_status) = match cache.get(&query) {
+        Some(page) => (page, "HIT"),
+        None => {
+            let generation = cache.generation();
+            let page = render_listing(&query, state)?;
+            cache.insert(generation, query, page.clone());
+            (page, "MISS")
         }
-        ret
     };
 
-    // If none were checked, include everything
-    let mut enabled_cnt = 0;
-    for s in &sources {
-        if s.enabled() {
-            enabled_cnt += 1;
+    if parts.method == Method::GET {
+        let fresh = etag_matches(&parts.headers, &page.etag)
+            || unmodified_since(&parts.headers, &page.last_modified);
+        if fresh {
+            return not_modified(&page.etag, "no-cache");
         }
     }
-    if enabled_cnt == 0 {
-        sources = EventSource::all().to_vec()
-    }
 
-    // Parse title search query
-    let title_like = if let Some(s) = params.get("title") {
-        if s.is_empty() {
-            "%"
-        } else {
-            s
-        }
-    } else {
-        "%"
-    };
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/html")
+        .header(header::ETAG, &page.etag)
+        .header(header::CACHE_CONTROL, "no-cache")
+        .header(header::LAST_MODIFIED, &page.last_modified)
+        .header("X-Cache", cache_status);
+    deflated_response(response, &parts.headers, page.body)
+}
 
+/// Query and render the listing for a set of filters
+fn render_listing(query: &ListingQuery, state: &AppState) -> AppResult<CachedPage> {
     // Grab connection
-    let conn = DB_POOL.get()?;
+    let conn = state.pool.get()?;
 
-    // Parse date search queries
+    // Fill in unspecified dates from the stored range
     let (mut begin_date, mut end_date) = total_event_range(&conn)?;
-    if let Some(s) = params.get("startdate") {
-        if !s.is_empty() {
-            begin_date = s.to_string();
-        }
+    if let Some(s) = &query.begin_date {
+        begin_date = s.clone();
     }
-    if let Some(s) = params.get("enddate") {
-        if !s.is_empty() {
-            end_date = s.to_string();
-        }
+    if let Some(s) = &query.end_date {
+        end_date = s.clone();
     }
 
     // Request event set
-    let events = filtered_events(&begin_date, &end_date, &sources, title_like, &conn)?;
+    let events = state.metrics.timed("filtered_events", || {
+        filtered_events(&begin_date, &end_date, query, &conn)
+    })?;
+    let ids = events.iter().map(|e| e.id).collect::<Vec<i32>>();
+    let mut event_tags = visible_tags(&conn, &ids)?;
+    let mut duplicates = duplicates_of(&conn, &ids)?;
+    let source_name = |name: &str| match state.sources.get(name) {
+        Some((source, _)) => source.to_string(),
+        None => name.to_string(),
+    };
+    let events = events
+        .into_iter()
+        .map(|event| ListedEvent {
+            tags: event_tags.remove(&event.id).unwrap_or_default(),
+            also_listed: duplicates
+                .remove(&event.id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|d| (source_name(&d.source), d.href))
+                .collect(),
+            event,
+        })
+        .collect();
+    let venues = all_venues(&conn)?;
+    let categories = tags_in_use(&conn)?;
     // Render template
-    let last_refresh = if let Some(r) = latest_refresh(&conn)? {
-        r.refresh_dt
-    } else {
-        "never".to_string()
+    let refresh = state
+        .metrics
+        .timed("latest_refresh", || latest_refresh(&conn))?;
+    let last_refresh = match &refresh {
+        Some(r) => r.refresh_dt.clone(),
+        None => "never".to_string(),
     };
     let template = IndexTemplate::new(
         &begin_date,
         &end_date,
         events,
-        title_like,
-        &sources,
+        query,
+        venues,
+        categories,
         &last_refresh,
     );
     let html = template.render()?;
-    html_str_handler(&html).await
+
+    // Validators follow the page itself, so any change to what's shown is picked up
+    let etag = format!("\"{}\"", content_hash(html.as_bytes()));
+    let last_modified = state.listing_cache.last_modified(query, &etag);
+
+    Ok(CachedPage {
+        body: deflate(html.as_bytes())?.into(),
+        etag,
+        last_modified,
+    })
 }
 
 /// Serve 404 page
 pub async fn four_oh_four() -> HandlerResult {
     let template = FourOhFourTemplate::default();
     let html = template.render()?;
-    html_str_handler(&html).await
+    html_str_handler(&html, Some(StatusCode::NOT_FOUND)).await
+}
+
+/// Load an event, or a 404 if there's no such event
+fn stored_event(state: &AppState, conn: &SqliteConnection, event_id: i32) -> AppResult<Event> {
+    state
+        .metrics
+        .timed("find_event", || find_event(conn, event_id))?
+        .ok_or_else(|| AppError::NotFound(format!("no event {}", event_id)))
+}
+
+/// Serve one event's page, with everything stored about it
+pub async fn event_page(state: &AppState, event_id: i32) -> HandlerResult {
+    let conn = state.pool.get()?;
+    let event = stored_event(state, &conn, event_id)?;
+    let source = match state.sources.get(&event.source) {
+        Some((source, _)) => source.to_string(),
+        None => event.source.clone(),
+    };
+    let venue = match &event.venue {
+        Some(name) => find_venue(&conn, name)?,
+        None => None,
+    };
+    let tags = visible_tags(&conn, &[event_id])?
+        .remove(&event_id)
+        .unwrap_or_default();
+    let duplicates = duplicates_of(&conn, &[event_id])?
+        .remove(&event_id)
+        .unwrap_or_default();
+    let revisions = revisions_of(&conn, event_id)?;
+    let template = EventTemplate::new(&event, source, venue, tags, duplicates, revisions);
+    html_str_handler(&template.render()?, None).await
+}
+
+/// Serve one event as an iCalendar file, to add it to a calendar
+pub async fn event_calendar(state: &AppState, event_id: i32) -> HandlerResult {
+    let event = stored_event(state, &*state.pool.get()?, event_id)?;
+    let mut ics = Vec::new();
+    write_events(&[event], Format::Ics, &mut ics)?;
+    bytes_handler(&ics, "text/calendar", None).await
+}
+
+/// Serve the metrics for Prometheus to scrape
+pub async fn metrics(state: &AppState) -> HandlerResult {
+    let mut response = string_handler(
+        &state.metrics.render(state)?,
+        "text/plain; version=0.0.4",
+        None,
+    )
+    .await?;
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, "no-store".parse()?);
+    Ok(response)
+}
+
+/// Report that the process is up
+pub async fn healthz() -> HandlerResult {
+    health_handler(&Health::live()).await
+}
+
+/// Report whether the service is ready for traffic, with each check's result
+pub async fn readyz(state: &AppState) -> HandlerResult {
+    health_handler(&Health::ready(state)).await
+}
+
+/// Serve a health report as JSON, failing with 503 so probes needn't read the body
+async fn health_handler(health: &Health) -> HandlerResult {
+    let json = serde_json::to_string(health).map_err(|e| AppError::Internal(e.into()))?;
+    let status = match health.status {
+        HealthStatus::Ok => StatusCode::OK,
+        HealthStatus::Fail => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let mut response = string_handler(&json, "application/json", Some(status)).await?;
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, "no-store".parse()?);
+    Ok(response)
+}
+
+/// Serve the generic error page with the given status
+pub async fn error_page(
+    status: StatusCode,
+    message: &str,
+    request_id: Option<&str>,
+) -> HandlerResult {
+    let template = ErrorTemplate::new(status, message, request_id);
+    let html = template.render()?;
+    html_str_handler(&html, Some(status)).await
+}
+
+/// Reject a known route requested with the wrong method
+pub async fn method_not_allowed(allowed: &[Method]) -> HandlerResult {
+    let allow = allowed
+        .iter()
+        .map(Method::as_str)
+        .collect::<Vec<&str>>()
+        .join(", ");
+    let mut response = error_page(
+        StatusCode::METHOD_NOT_ALLOWED,
+        &format!("This page only accepts {}", allow),
+        None,
+    )
+    .await?;
+    response.headers_mut().insert(header::ALLOW, allow.parse()?);
+    Ok(response)
+}
+
+/// Read an event's tags, or with `PUT` replace them with a JSON array of names
+/// Replaced tags are pinned, so re-tagging the event doesn't undo the edit.
+pub async fn event_tags(req: Request<Body>, state: &AppState, event_id: i32) -> HandlerResult {
+    let conn = state.pool.get()?;
+    stored_event(state, &conn, event_id)?;
+    if req.method() == Method::PUT {
+        let body = hyper::body::to_bytes(req.into_body()).await?;
+        let names = serde_json::from_slice::<Vec<String>>(&body)
+            .map_err(|e| AppError::Validation(format!("expected a JSON array of tags: {}", e)))?
+            .iter()
+            .map(|name| normalize_tag(name))
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<String>>();
+        set_manual_tags(&conn, event_id, &names)?;
+        state.listing_cache.invalidate();
+        info!("Set tags of event {} to {:?}", event_id, names);
+    }
+    let tags = visible_tags(&conn, &[event_id])?
+        .remove(&event_id)
+        .unwrap_or_default();
+    let json = serde_json::to_string(&tags).map_err(|e| AppError::Internal(e.into()))?;
+    string_handler(&json, "application/json", None).await
 }
 
 /// Request a re-scrape
-pub async fn refresh_events() -> HandlerResult {
-    // Only refresh if it's been more than 24h
+pub async fn refresh_events(state: &AppState) -> HandlerResult {
+    // Only refresh if it's been more than the configured interval
     // If there's no refresh, we'll just continue on
-    let conn = DB_POOL.get()?;
-    if let Some(last_refresh) = latest_refresh(&conn)? {
+    if let Some(last_refresh) = latest_refresh(&*state.pool.get()?)? {
         let now = Utc::now();
         let last = DateTime::parse_from_rfc3339(&last_refresh.refresh_dt)?;
         let duration = now.timestamp() - last.timestamp();
-        if duration < (60 * 60 * 24) {
-            // If it hasn't been 24 hours since the last scrape, do nothing
+        if duration < state.opt.refresh_interval as i64 {
+            // If it hasn't been long enough since the last scrape, do nothing
             return Ok(Response::default());
         }
     }
-    let total_added = EventSource::scrape_all_events().await?;
-    info!("Added {} new events", total_added);
+    let totals = EventSource::scrape_all_events(state).await?;
+    info!(
+        "Added {} new events, {} sources unchanged",
+        totals.added, totals.unchanged
+    );
     create_refresh(
-        &conn,
-        total_added.try_into().unwrap(), // I would be VERY surprised if we ever overflow an integer with this count
+        &*state.pool.get()?,
+        totals.added.try_into().unwrap(), // I would be VERY surprised if we ever overflow an integer with this count
+        totals.unchanged.try_into().unwrap(),
     )?;
+    // The listing shows when the latest refresh happened
+    state.listing_cache.invalidate();
     Ok(Response::default())
 }
@@ -0,0 +1,104 @@
+// health.rs
+// Liveness and readiness checks, for the deployment to probe
+
+use super::*;
+use chrono::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use serde_derive::Serialize;
+use std::{collections::BTreeMap, time::Duration};
+
+/// Longest a readiness probe waits for a free database connection
+const POOL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Whether a check, or the whole service, is working
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Ok,
+    Fail,
+}
+
+/// Outcome of one check, with what was found
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Check {
+    pub status: HealthStatus,
+    pub detail: String,
+}
+
+impl Check {
+    fn ok(detail: impl Into<String>) -> Self {
+        Self {
+            status: HealthStatus::Ok,
+            detail: detail.into(),
+        }
+    }
+    fn fail(detail: impl Into<String>) -> Self {
+        Self {
+            status: HealthStatus::Fail,
+            detail: detail.into(),
+        }
+    }
+}
+
+/// Overall status and each check it came from, as served by `/healthz` and `/readyz`
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Health {
+    pub status: HealthStatus,
+    pub checks: BTreeMap<&'static str, Check>,
+}
+
+impl Health {
+    fn new(checks: BTreeMap<&'static str, Check>) -> Self {
+        let status = if checks.values().all(|c| c.status == HealthStatus::Ok) {
+            HealthStatus::Ok
+        } else {
+            HealthStatus::Fail
+        };
+        Self { status, checks }
+    }
+    /// The process is up and answering, which is all it takes to be live
+    pub fn live() -> Self {
+        Self::new(BTreeMap::new())
+    }
+    /// Whether the service can answer with its data: a connection is free, the schema is
+    /// current and, if `max_refresh_age` is set, the last refresh is recent enough
+    pub fn ready(state: &AppState) -> Self {
+        let mut checks = BTreeMap::new();
+        match state.pool.get_timeout(POOL_TIMEOUT) {
+            Ok(conn) => {
+                checks.insert("database", Check::ok("connected"));
+                let migrations = match migrations_current(&conn) {
+                    Ok(true) => Check::ok("up to date"),
+                    Ok(false) => Check::fail("migrations are pending"),
+                    Err(e) => Check::fail(e.to_string()),
+                };
+                checks.insert("migrations", migrations);
+                if let Some(max_age) = state.opt.max_refresh_age {
+                    let refresh = refresh_check(&conn, max_age)
+                        .unwrap_or_else(|e| Check::fail(e.to_string()));
+                    checks.insert("refresh", refresh);
+                }
+            }
+            Err(e) => {
+                checks.insert("database", Check::fail(AppError::from(e).to_string()));
+            }
+        }
+        Self::new(checks)
+    }
+}
+
+/// Whether the latest refresh happened within `max_age` seconds
+fn refresh_check(conn: &SqliteConnection, max_age: u64) -> AppResult<Check> {
+    let refresh = match latest_refresh(conn)? {
+        Some(refresh) => refresh,
+        None => return Ok(Check::fail("never refreshed")),
+    };
+    let last = DateTime::parse_from_rfc3339(&refresh.refresh_dt)?;
+    let age = Utc::now().timestamp() - last.timestamp();
+    let detail = format!("last refreshed {} seconds ago", age);
+    if age > max_age as i64 {
+        Ok(Check::fail(detail))
+    } else {
+        Ok(Check::ok(detail))
+    }
+}
@@ -0,0 +1,228 @@
+// jsonld.rs
+// Extracting schema.org Events embedded in a page as JSON-LD
+
+use super::*;
+use chrono::prelude::*;
+use chrono_tz::Europe::Berlin;
+use log::warn;
+use select::predicate::Attr;
+use serde_json::Value;
+
+/// Every schema.org Event, or subtype such as MusicEvent, embedded in the page
+pub fn parse_json_ld_events(page: &Page, source: &EventSource) -> AppResult<ParsedEvents> {
+    let mut objects = Vec::new();
+    for script in page.document.find(Attr("type", "application/ld+json")) {
+        // One broken block shouldn't hide the others
+        match serde_json::from_str::<Value>(&script.text()) {
+            Ok(value) => collect_events(value, &mut objects),
+            Err(e) => warn!("{}: skipping malformed JSON-LD: {}", source.as_str(), e),
+        }
+    }
+    let mut ret = ParsedEvents::default();
+    for object in &objects {
+        // Nor should one event missing its name or date
+        match to_new_event(object, page, source) {
+            Ok(event) => ret.events.push(event),
+            Err(e) => {
+                warn!("{}: skipping JSON-LD event: {}", source.as_str(), e);
+                ret.failed += 1;
+            }
+        }
+    }
+    Ok(ret)
+}
+
+/// Gather Event objects from a JSON-LD value, looking inside arrays and `@graph`
+fn collect_events(value: Value, out: &mut Vec<Value>) {
+    match value {
+        Value::Array(values) => {
+            for value in values {
+                collect_events(value, out);
+            }
+        }
+        Value::Object(mut object) => {
+            if let Some(graph) = object.remove("@graph") {
+                collect_events(graph, out);
+            }
+            let is_event = match object.get("@type") {
+                Some(Value::String(t)) => t.ends_with("Event"),
+                Some(Value::Array(types)) => types
+                    .iter()
+                    .any(|t| t.as_str().is_some_and(|t| t.ends_with("Event"))),
+                _ => false,
+            };
+            if is_event {
+                out.push(Value::Object(object));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Map one Event object onto our columns
+/// The location's name is the venue, and the price makes up the subtitle.
+fn to_new_event(event: &Value, page: &Page, source: &EventSource) -> AppResult<NewEvent> {
+    let title = text(event, "name").ok_or_else(|| AppError::parse("JSON-LD event has no name"))?;
+    let start = text(event, "startDate")
+        .ok_or_else(|| AppError::parse(format!("JSON-LD event {:?} has no startDate", title)))?;
+    let event_date = normalize_date(&start)?;
+    let event_end_date = match text(event, "endDate") {
+        Some(end) => Some(normalize_date(&end)?),
+        None => None,
+    };
+    let href = match text(event, "url") {
+        Some(url) => page.resolve(&url)?,
+        None => page.url.to_string(),
+    };
+    let synopsis = text(event, "description").unwrap_or_default();
+
+    Ok(NewEvent {
+        venue: location(event),
+        ..NewEvent::new(
+            &title,
+            price(event),
+            &href,
+            &synopsis,
+            &event_date,
+            event_end_date,
+            source.clone(),
+        )
+    })
+}
+
+/// A trimmed, non-empty string property
+fn text(object: &Value, key: &str) -> Option<String> {
+    object
+        .get(key)
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+}
+
+/// Name of the event's location, which may be a bare string, a Place, or several
+fn location(event: &Value) -> Option<String> {
+    let place = match event.get("location")? {
+        Value::Array(places) => places.first()?,
+        place => place,
+    };
+    match place {
+        Value::String(name) => Some(name.trim().to_string()).filter(|s| !s.is_empty()),
+        place => text(place, "name"),
+    }
+}
+
+/// Ticket price from the first Offer, e.g. "15 EUR" or "from 10 EUR"
+fn price(event: &Value) -> Option<String> {
+    let offer = match event.get("offers")? {
+        Value::Array(offers) => offers.first()?,
+        offer => offer,
+    };
+    let amount = |key: &str| match offer.get(key)? {
+        Value::Number(n) => Some(n.to_string()),
+        Value::String(s) => Some(s.trim().to_string()).filter(|s| !s.is_empty()),
+        _ => None,
+    };
+    let currency = text(offer, "priceCurrency")
+        .map(|c| format!(" {}", c))
+        .unwrap_or_default();
+    match (amount("price"), amount("lowPrice")) {
+        (Some(price), _) => Some(format!("{}{}", price, currency)),
+        (None, Some(low)) => Some(format!("from {}{}", low, currency)),
+        (None, None) => None,
+    }
+}
+
+/// Convert an ISO 8601 date or datetime into the stored format, in Berlin time
+/// Times without an offset are taken to be Berlin time already.
+pub fn normalize_date(s: &str) -> AppResult<String> {
+    if let Ok(dt) = DateTime::parse_from_rfc3339(s) {
+        return Ok(dt.with_timezone(&Berlin).naive_local().to_string());
+    }
+    for fmt in &["%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"] {
+        if let Ok(dt) = NaiveDateTime::parse_from_str(s, fmt) {
+            return Ok(dt.to_string());
+        }
+    }
+    NaiveDate::parse_from_str(s, "%F")
+        .map(|d| d.to_string())
+        .map_err(|e| AppError::parse(format!("bad JSON-LD date {:?}: {}", s, e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_parse_json_ld() {
+        let html = r#"<html><head>
+<script type="application/ld+json">{"@context": "https://schema.org", "@graph": [
+  {"@type": "Organization", "name": "Not an event"},
+  {"@type": "Event", "name": "Date to be announced"},
+  {"@type": "Event", "name": "Click Me", "startDate": "2020-03-08", "url": "javascript:alert(1)"},
+  {"@type": "MusicEvent", "name": "Late Concert", "startDate": "2020-03-06T21:00:00+01:00",
+   "url": "/en/late-concert", "description": "Strings",
+   "location": {"@type": "Place", "name": "Hall A"},
+   "offers": [{"@type": "Offer", "price": 15, "priceCurrency": "EUR"}]}
+]}</script>
+<script type="application/ld+json">[{"@type": ["Event"], "name": "Open Day",
+  "startDate": "2020-03-07", "endDate": "2020-03-08", "location": "Garden"},
+  {"@type": "Event", "name": "Artist Talk", "startDate": "2020-03-10T19:00:00Z"},
+  {"@type": "Event", "name": "Livestream", "startDate": "2020-03-11T23:30:00+05:00"}]</script>
+<script type="application/ld+json">{ not json </script>
+</head></html>"#;
+        let page = Page::new("https://venue.example/en/programme".parse().unwrap(), html);
+        let source = EventSource::JsonLd("Venue".into(), true);
+        let parsed = parse_json_ld_events(&page, &source).unwrap();
+
+        assert_eq!(parsed.failed, 2);
+        assert_eq!(
+            parsed.events,
+            vec![
+                NewEvent {
+                    venue: Some("Hall A".into()),
+                    ..NewEvent::new(
+                        "Late Concert",
+                        Some("15 EUR".into()),
+                        "https://venue.example/en/late-concert",
+                        "Strings",
+                        "2020-03-06 21:00:00",
+                        None,
+                        source.clone(),
+                    )
+                },
+                NewEvent {
+                    venue: Some("Garden".into()),
+                    ..NewEvent::new(
+                        "Open Day",
+                        None,
+                        "https://venue.example/en/programme",
+                        "",
+                        "2020-03-07",
+                        Some("2020-03-08".into()),
+                        source.clone(),
+                    )
+                },
+                NewEvent::new(
+                    "Artist Talk",
+                    None,
+                    "https://venue.example/en/programme",
+                    "",
+                    "2020-03-10 20:00:00",
+                    None,
+                    source.clone(),
+                ),
+                NewEvent::new(
+                    "Livestream",
+                    None,
+                    "https://venue.example/en/programme",
+                    "",
+                    "2020-03-11 19:30:00",
+                    None,
+                    source.clone(),
+                ),
+            ]
+        );
+    }
+}
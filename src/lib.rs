@@ -0,0 +1,64 @@
+// lib.rs
+// Everything but the entry point, so the integration tests can drive the service
+
+// diesel 1.x derives emit their impls inside an anonymous const
+#![allow(non_local_definitions)]
+
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate diesel_migrations;
+
+mod assets;
+mod cache;
+mod commands;
+mod config;
+mod db;
+mod dedup;
+mod diff;
+mod error;
+mod export;
+mod feed;
+mod fetch;
+mod files;
+mod handlers;
+mod health;
+mod jsonld;
+mod metrics;
+mod middleware;
+mod models;
+mod robots;
+mod router;
+mod schema;
+mod scrape;
+mod state;
+mod tagging;
+mod templates;
+
+// Re-exports for more convenient in-crate `use`
+
+pub use assets::*;
+pub use cache::*;
+pub use commands::*;
+pub use config::*;
+pub use db::*;
+pub use dedup::*;
+pub use diff::*;
+pub use error::*;
+pub use export::*;
+pub use feed::*;
+pub use fetch::*;
+pub use files::*;
+pub use handlers::*;
+pub use health::*;
+pub use jsonld::*;
+pub use metrics::*;
+pub use middleware::*;
+pub use models::*;
+pub use robots::*;
+pub use router::*;
+pub use schema::*;
+pub use scrape::*;
+pub use state::*;
+pub use tagging::*;
+pub use templates::*;
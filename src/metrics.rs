@@ -0,0 +1,357 @@
+// metrics.rs
+// Prometheus metrics, collected in memory and served on /metrics
+
+use super::*;
+use chrono::prelude::*;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Upper bounds in seconds for request and query latencies
+const LATENCY_BUCKETS: &[f64] = &[
+    0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// Upper bounds in seconds for whole scrapes, which wait on the fetch delay
+const SCRAPE_BUCKETS: &[f64] = &[0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+/// Observations counted into cumulative buckets
+#[derive(Debug, Clone)]
+struct Histogram {
+    bounds: &'static [f64],
+    /// Observations at or under each bound, in the same order
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bound, bucket) in self.bounds.iter().zip(self.buckets.iter_mut()) {
+            if secs <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+    /// Append the bucket, sum and count samples for one set of labels
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        for (bound, bucket) in self.bounds.iter().zip(&self.buckets) {
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{}le=\"{}\"}} {}",
+                name,
+                with_comma(labels),
+                bound,
+                bucket
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{}_bucket{{{}le=\"+Inf\"}} {}",
+            name,
+            with_comma(labels),
+            self.count
+        );
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+/// What scraping one source has done so far
+#[derive(Debug, Clone)]
+struct SourceMetrics {
+    duration: Histogram,
+    parsed: u64,
+    inserted: u64,
+    /// Items the parser rejected
+    failed: u64,
+}
+
+impl Default for SourceMetrics {
+    fn default() -> Self {
+        Self {
+            duration: Histogram::new(SCRAPE_BUCKETS),
+            parsed: 0,
+            inserted: 0,
+            failed: 0,
+        }
+    }
+}
+
+/// Counters and histograms for the server, database and scraper
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Keyed by route pattern, method and status
+    requests: Mutex<BTreeMap<(String, String, u16), Histogram>>,
+    /// Keyed by the name of the db function
+    queries: Mutex<BTreeMap<&'static str, Histogram>>,
+    /// Keyed by source name
+    scrapes: Mutex<BTreeMap<String, SourceMetrics>>,
+}
+
+impl Metrics {
+    /// Record an answered request
+    pub fn observe_request(&self, route: &str, method: &str, status: u16, elapsed: Duration) {
+        self.requests
+            .lock()
+            .unwrap()
+            .entry((route.into(), method.into(), status))
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(elapsed);
+    }
+    /// Record a finished database query
+    pub fn observe_query(&self, name: &'static str, elapsed: Duration) {
+        self.queries
+            .lock()
+            .unwrap()
+            .entry(name)
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS))
+            .observe(elapsed);
+    }
+    /// Run a database query, recording how long it took under `name`
+    pub fn timed<T>(
+        &self,
+        name: &'static str,
+        query: impl FnOnce() -> AppResult<T>,
+    ) -> AppResult<T> {
+        let started = Instant::now();
+        let ret = query();
+        self.observe_query(name, started.elapsed());
+        ret
+    }
+    /// Record one source's scrape, with how many events were parsed, inserted and rejected
+    pub fn observe_scrape(&self, source: &str, elapsed: Duration, counts: &SourceCounts) {
+        let mut scrapes = self.scrapes.lock().unwrap();
+        let entry = scrapes.entry(source.into()).or_default();
+        entry.duration.observe(elapsed);
+        entry.parsed += counts.parsed as u64;
+        entry.inserted += counts.added as u64;
+        entry.failed += counts.failed as u64;
+    }
+    /// Everything recorded, plus the pool and refresh gauges, in the Prometheus text format
+    pub fn render(&self, state: &AppState) -> AppResult<String> {
+        let mut out = String::new();
+
+        let requests = self.requests.lock().unwrap().clone();
+        header(
+            &mut out,
+            "dalia_http_requests_total",
+            "counter",
+            "Requests answered, by route, method and status",
+        );
+        for ((route, method, status), histogram) in &requests {
+            let _ = writeln!(
+                out,
+                "dalia_http_requests_total{{{}}} {}",
+                request_labels(route, method, *status),
+                histogram.count
+            );
+        }
+        header(
+            &mut out,
+            "dalia_http_request_duration_seconds",
+            "histogram",
+            "Time taken to answer requests, by route, method and status",
+        );
+        for ((route, method, status), histogram) in &requests {
+            histogram.render(
+                &mut out,
+                "dalia_http_request_duration_seconds",
+                &request_labels(route, method, *status),
+            );
+        }
+
+        header(
+            &mut out,
+            "dalia_db_query_duration_seconds",
+            "histogram",
+            "Time taken by database queries, by query",
+        );
+        for (query, histogram) in self.queries.lock().unwrap().iter() {
+            histogram.render(
+                &mut out,
+                "dalia_db_query_duration_seconds",
+                &format!("query=\"{}\"", query),
+            );
+        }
+
+        let pool = state.pool.state();
+        let gauges = [
+            (
+                "dalia_db_pool_connections",
+                "Open database connections",
+                pool.connections,
+            ),
+            (
+                "dalia_db_pool_idle_connections",
+                "Open database connections not in use",
+                pool.idle_connections,
+            ),
+            (
+                "dalia_db_pool_max_connections",
+                "Most database connections the pool will open",
+                state.pool.max_size(),
+            ),
+        ];
+        for (name, help, value) in &gauges {
+            header(&mut out, name, "gauge", help);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+
+        let scrapes = self.scrapes.lock().unwrap().clone();
+        header(
+            &mut out,
+            "dalia_scrape_duration_seconds",
+            "histogram",
+            "Time taken to fetch and store each source, by source",
+        );
+        for (source, metrics) in &scrapes {
+            metrics.duration.render(
+                &mut out,
+                "dalia_scrape_duration_seconds",
+                &source_label(source),
+            );
+        }
+        source_counter(
+            &mut out,
+            &scrapes,
+            "dalia_events_parsed_total",
+            "Events parsed from changed source pages, by source",
+            |m| m.parsed,
+        );
+        source_counter(
+            &mut out,
+            &scrapes,
+            "dalia_events_inserted_total",
+            "New events stored, by source",
+            |m| m.inserted,
+        );
+        source_counter(
+            &mut out,
+            &scrapes,
+            "dalia_events_failed_total",
+            "Items on source pages that couldn't be read as events and were skipped, by source",
+            |m| m.failed,
+        );
+
+        // Read from the database, so scrapes by other processes and before a restart count
+        header(
+            &mut out,
+            "dalia_source_last_success_age_seconds",
+            "gauge",
+            "Seconds since each source's page was last fetched and stored",
+        );
+        let conn = state.pool.get()?;
+        let now = Utc::now();
+        for source in state.sources.all() {
+            let config = match state.sources.get(source.as_str()) {
+                Some((_, config)) => config,
+                None => continue,
+            };
+            if let Some(page) = source_page(&conn, &source.url_calendar(config))? {
+                let fetched = DateTime::parse_from_rfc3339(&page.fetched_dt)?;
+                let _ = writeln!(
+                    out,
+                    "dalia_source_last_success_age_seconds{{{}}} {}",
+                    source_label(source.as_str()),
+                    (now - fetched.with_timezone(&Utc)).num_seconds()
+                );
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// A counter with one sample per scraped source
+fn source_counter(
+    out: &mut String,
+    scrapes: &BTreeMap<String, SourceMetrics>,
+    name: &str,
+    help: &str,
+    value: fn(&SourceMetrics) -> u64,
+) {
+    header(out, name, "counter", help);
+    for (source, metrics) in scrapes {
+        let _ = writeln!(
+            out,
+            "{}{{{}}} {}",
+            name,
+            source_label(source),
+            value(metrics)
+        );
+    }
+}
+
+/// HELP and TYPE lines introducing a metric
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn request_labels(route: &str, method: &str, status: u16) -> String {
+    format!(
+        "route=\"{}\",method=\"{}\",status=\"{}\"",
+        escape(route),
+        method,
+        status
+    )
+}
+
+fn source_label(source: &str) -> String {
+    format!("source=\"{}\"", escape(source))
+}
+
+/// Labels followed by a comma, ready for another label, or nothing if there are none
+fn with_comma(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{},", labels)
+    }
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(5));
+        let mut out = String::new();
+        histogram.render(&mut out, "x", "route=\"/\"");
+        assert_eq!(
+            out,
+            "x_bucket{route=\"/\",le=\"0.1\"} 1\n\
+             x_bucket{route=\"/\",le=\"1\"} 2\n\
+             x_bucket{route=\"/\",le=\"+Inf\"} 3\n\
+             x_sum{route=\"/\"} 5.55\n\
+             x_count{route=\"/\"} 3\n"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+// middleware.rs
+// Hooks that run around route handlers
+
+use super::*;
+use hyper::{body::HttpBody, header, Body, HeaderMap, Request, Response, StatusCode};
+use log::{log, Level};
+use serde_derive::Serialize;
+use std::{fmt, time::Instant};
+use uuid::Uuid;
+
+/// Log target for access log lines
+pub const ACCESS_LOG: &str = "dalia_challenge::access";
+
+/// ID given to each request, echoed back as `X-Request-Id`
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestId(pub String);
+
+/// One finished request, as logged
+#[derive(Debug, Serialize)]
+struct AccessEntry<'a> {
+    request_id: &'a str,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    /// Body length as sent, if known up front
+    bytes: Option<u64>,
+    latency_ms: f64,
+    user_agent: Option<&'a str>,
+}
+
+/// Tag each request with an ID, and log it once answered
+/// Requests at or over `slow_request_ms` are logged as warnings.
+pub fn access_log<'a>(mut req: Request<Body>, state: &'a AppState, next: Next) -> BoxFuture<'a> {
+    let start = Instant::now();
+    let request_id = Uuid::new_v4().to_string();
+    req.extensions_mut().insert(RequestId(request_id.clone()));
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .and_then(|h| h.to_str().ok())
+        .map(String::from);
+    Box::pin(async move {
+        let mut response = next.run(req, state).await?;
+        let latency = start.elapsed();
+        response
+            .headers_mut()
+            .insert("x-request-id", request_id.parse()?);
+        let entry = AccessEntry {
+            request_id: &request_id,
+            method: method.as_str(),
+            path: &path,
+            status: response.status().as_u16(),
+            bytes: response.body().size_hint().exact(),
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            user_agent: user_agent.as_deref(),
+        };
+        let level = if latency.as_millis() >= u128::from(state.opt.slow_request_ms) {
+            Level::Warn
+        } else {
+            Level::Info
+        };
+        if state.opt.log_format == "json" {
+            let line = serde_json::to_string(&entry).map_err(|e| AppError::Internal(e.into()))?;
+            log!(target: ACCESS_LOG, level, "{}", line);
+        } else {
+            log!(target: ACCESS_LOG, level, "{}", entry);
+        }
+        Ok(response)
+    })
+}
+
+/// Record each request's latency under the route it matched
+pub fn record_metrics<'a>(req: Request<Body>, state: &'a AppState, next: Next) -> BoxFuture<'a> {
+    let start = Instant::now();
+    let method = req.method().clone();
+    let route = match req.extensions().get::<MatchedRoute>() {
+        Some(route) => route.0.clone(),
+        None => "unmatched".into(),
+    };
+    Box::pin(async move {
+        let response = next.run(req, state).await?;
+        state.metrics.observe_request(
+            &route,
+            method.as_str(),
+            response.status().as_u16(),
+            start.elapsed(),
+        );
+        Ok(response)
+    })
+}
+
+/// DEFLATE compress textual responses the handler didn't already compress, for clients
+/// that accept it
+pub fn compress<'a>(req: Request<Body>, state: &'a AppState, next: Next) -> BoxFuture<'a> {
+    let accepted = accepts_deflate(req.headers());
+    Box::pin(async move {
+        let mut response = next.run(req, state).await?;
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|h| h.to_str().ok())
+            .unwrap_or_default();
+        let bodiless = [StatusCode::NO_CONTENT, StatusCode::NOT_MODIFIED];
+        // Byte ranges refer to the uncompressed body, so files that offer them are left alone
+        if bodiless.contains(&response.status())
+            || !compressible(content_type)
+            || response.headers().contains_key(header::CONTENT_ENCODING)
+            || response.headers().contains_key(header::VARY)
+            || response.headers().contains_key(header::ACCEPT_RANGES)
+        {
+            return Ok(response);
+        }
+        // Shared caches must keep the plain and compressed bodies apart
+        response.headers_mut().insert(
+            header::VARY,
+            header::HeaderValue::from_static("Accept-Encoding"),
+        );
+        if !accepted {
+            return Ok(response);
+        }
+        let (mut parts, body) = response.into_parts();
+        let body = deflate(&hyper::body::to_bytes(body).await?)?;
+        parts.headers.remove(header::CONTENT_LENGTH);
+        parts.headers.insert(
+            header::CONTENT_ENCODING,
+            header::HeaderValue::from_static("deflate"),
+        );
+        Ok(Response::from_parts(parts, Body::from(body)))
+    })
+}
+
+/// Only let through requests carrying the admin token
+pub fn require_admin<'a>(req: Request<Body>, state: &'a AppState, next: Next) -> BoxFuture<'a> {
+    match authorize_admin(req.headers(), &state.opt) {
+        Ok(()) => next.run(req, state),
+        Err(e) => Box::pin(async { Err(e) }),
+    }
+}
+
+/// Keep responses out of every cache, unless the handler chose a policy itself
+pub fn no_store<'a>(req: Request<Body>, state: &'a AppState, next: Next) -> BoxFuture<'a> {
+    Box::pin(async move {
+        let mut response = next.run(req, state).await?;
+        if !response.headers().contains_key(header::CACHE_CONTROL) {
+            response.headers_mut().insert(
+                header::CACHE_CONTROL,
+                header::HeaderValue::from_static("no-store"),
+            );
+        }
+        Ok(response)
+    })
+}
+
+impl fmt::Display for AccessEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{}] {} {} {} {}B {:.1}ms {:?}",
+            self.request_id,
+            self.method,
+            self.path,
+            self.status,
+            self.bytes.map_or("-".into(), |b| b.to_string()),
+            self.latency_ms,
+            self.user_agent.unwrap_or("-")
+        )
+    }
+}
+
+/// Whether a body of this type shrinks when compressed
+fn compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+json")
+        || essence.ends_with("+xml")
+        || [
+            "application/json",
+            "application/javascript",
+            "application/xml",
+        ]
+        .contains(&essence)
+}
+
+/// Check the request carries the admin token as a bearer token
+/// Without a configured token the admin API doesn't exist.
+fn authorize_admin(headers: &HeaderMap, opt: &Opt) -> AppResult<()> {
+    let token = match &opt.admin_token {
+        Some(token) => token,
+        None => return Err(AppError::NotFound("the admin API is disabled".into())),
+    };
+    let given = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    // Compare every byte, so the time taken doesn't reveal how much matched
+    let matches = given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0;
+    if matches {
+        Ok(())
+    } else {
+        Err(AppError::Unauthorized(
+            "This needs a valid admin token.".into(),
+        ))
+    }
+}
@@ -1,8 +1,9 @@
 // Rust types for DB records
 
 use super::*;
+use serde_derive::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Queryable)]
+#[derive(Debug, Clone, PartialEq, Queryable, Serialize)]
 pub struct Event {
     pub id: i32,
     pub href: String,
@@ -12,58 +13,109 @@ pub struct Event {
     pub event_date: String,             // as ISO 8601 date string
     pub event_end_date: Option<String>, // TODO date type once working
     pub source: String,
+    /// Name of the venue it's held at, if known
+    pub venue: Option<String>,
+    /// The event this one duplicates, as listed by another source
+    pub canonical_id: Option<i32>,
+    /// When it was first scraped, as RFC 3339
+    pub first_seen: Option<String>,
+    /// When a scrape last changed it, as RFC 3339
+    pub last_updated: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Insertable)]
+#[derive(Debug, Clone, PartialEq, Insertable, AsChangeset, Deserialize, Serialize)]
 #[table_name = "events"]
-pub struct NewEvent<'a> {
-    pub href: &'a str,
-    pub title: &'a str,
+#[changeset_options(treat_none_as_null = "true")]
+pub struct NewEvent {
+    pub href: String,
+    pub title: String,
     pub subtitle: Option<String>,
-    pub synopsis: &'a str,
-    pub event_date: &'a str,
+    pub synopsis: String,
+    pub event_date: String,
     pub event_end_date: Option<String>,
-    pub source: &'a str,
+    pub source: String,
+    /// Filled in from the source's default venue unless the parser knows better
+    #[serde(default)]
+    pub venue: Option<String>,
 }
 
-impl<'a> PartialEq<NewEvent<'a>> for Event {
+impl PartialEq<NewEvent> for Event {
     fn eq(&self, rhs: &NewEvent) -> bool {
         self.href == rhs.href
             && self.title == rhs.title
             && self.synopsis == rhs.synopsis
             && self.event_date == rhs.event_date
             && self.source == rhs.source
+            && self.venue == rhs.venue
     }
 }
 
-impl<'a> NewEvent<'a> {
+impl NewEvent {
     pub fn new(
-        title: &'a str,
+        title: &str,
         subtitle: Option<String>,
-        href: &'a str,
-        synopsis: &'a str,
-        event_date: &'a str,
+        href: &str,
+        synopsis: &str,
+        event_date: &str,
         event_end_date: Option<String>,
         source: EventSource,
     ) -> Self {
         Self {
-            href,
-            title,
+            href: href.into(),
+            title: title.into(),
             subtitle,
-            synopsis,
-            event_date,
+            synopsis: synopsis.into(),
+            event_date: event_date.into(),
             event_end_date,
-            source: source.as_str(),
+            source: source.as_str().into(),
+            venue: None,
         }
     }
 }
 
+/// One category on one event
+#[derive(Debug, Clone, PartialEq, Queryable, Insertable, Serialize)]
+#[table_name = "event_tags"]
+pub struct EventTag {
+    pub event_id: i32,
+    pub tag: String,
+    /// Set through the admin API rather than by the tagging rules
+    pub manual: bool,
+    /// Hidden by hand, so the rules don't add it back
+    pub removed: bool,
+}
+
+/// One field of an event changed by a scrape
+#[derive(Debug, Clone, PartialEq, Queryable, Serialize)]
+pub struct Revision {
+    pub id: i32,
+    pub event_id: i32,
+    /// As RFC 3339
+    pub revised_dt: String,
+    pub field: String,
+    pub old_value: Option<String>,
+    pub new_value: Option<String>,
+}
+
+/// A place events happen, such as a gallery or one floor of a club
+#[derive(Debug, Clone, PartialEq, Queryable, Serialize)]
+pub struct Venue {
+    pub name: String,
+    pub address: Option<String>,
+    pub district: Option<String>,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    pub website: Option<String>,
+}
+
 /// Struct for keeping track of data scrapes
 #[derive(Debug, Clone, PartialEq, Queryable)]
 pub struct Refresh {
     pub id: i32,
     pub refresh_dt: String,
     pub total_added: i32,
+    /// Sources skipped because their page hadn't changed since the last fetch
+    pub sources_unchanged: i32,
 }
 
 #[derive(Debug, PartialEq, Insertable)]
@@ -71,4 +123,17 @@ pub struct Refresh {
 pub struct NewRefresh<'a> {
     pub refresh_dt: &'a str,
     pub total_added: i32,
+    pub sources_unchanged: i32,
+}
+
+/// Validators from the last fetch of a source page, sent back to ask whether it changed
+#[derive(Debug, Clone, PartialEq, Queryable, Insertable)]
+#[table_name = "source_pages"]
+pub struct SourcePage {
+    pub url: String,
+    pub etag: Option<String>,
+    /// As received, an HTTP-date
+    pub last_modified: Option<String>,
+    pub content_hash: String,
+    pub fetched_dt: String,
 }
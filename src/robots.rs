@@ -0,0 +1,148 @@
+// robots.rs
+// Minimal robots.txt parsing and matching
+
+/// The rules from one robots.txt that apply to us
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Robots {
+    /// (allow, path pattern) pairs from the group matching our user agent
+    rules: Vec<(bool, String)>,
+}
+
+impl Robots {
+    /// Everything allowed - used when a site has no robots.txt
+    pub fn allow_all() -> Self {
+        Self::default()
+    }
+    /// Nothing allowed - used when robots.txt couldn't be read
+    pub fn disallow_all() -> Self {
+        Self {
+            rules: vec![(false, "/".into())],
+        }
+    }
+    /// Parse a robots.txt, keeping the group for `user_agent` or else the `*` group
+    /// Groups are matched case-insensitively on the product token, e.g. `dalia-challenge`.
+    pub fn parse(body: &str, user_agent: &str) -> Self {
+        let token = user_agent
+            .split(|c: char| c == '/' || c.is_whitespace())
+            .next()
+            .unwrap_or_default()
+            .to_lowercase();
+        let mut ours = Vec::new();
+        let mut wildcard = Vec::new();
+        let mut found_ours = false;
+
+        // Consecutive User-agent lines share the rules that follow them
+        let mut group_agents: Vec<String> = Vec::new();
+        let mut in_rules = false;
+        for line in body.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            let (key, value) = match line.split_once(':') {
+                Some((k, v)) => (k.trim().to_lowercase(), v.trim()),
+                None => continue,
+            };
+            match key.as_str() {
+                "user-agent" => {
+                    if in_rules {
+                        group_agents.clear();
+                        in_rules = false;
+                    }
+                    let agent = value.to_lowercase();
+                    // Naming us is enough to pick this group, even if it has no rules
+                    if agent == token {
+                        found_ours = true;
+                    }
+                    group_agents.push(agent);
+                }
+                "allow" | "disallow" => {
+                    in_rules = true;
+                    // An empty Disallow allows everything
+                    if value.is_empty() {
+                        continue;
+                    }
+                    let rule = (key == "allow", value.to_string());
+                    if group_agents.contains(&token) {
+                        ours.push(rule.clone());
+                    }
+                    if group_agents.iter().any(|a| a == "*") {
+                        wildcard.push(rule);
+                    }
+                }
+                _ => {}
+            }
+        }
+        Self {
+            rules: if found_ours { ours } else { wildcard },
+        }
+    }
+    /// Whether we may fetch `path`, which should include any query string
+    /// The longest matching rule wins, with Allow winning ties.
+    pub fn allowed(&self, path: &str) -> bool {
+        self.rules
+            .iter()
+            .filter(|(_, pattern)| matches(pattern, path))
+            .max_by_key(|(allow, pattern)| (pattern.len(), *allow))
+            .is_none_or(|(allow, _)| *allow)
+    }
+}
+
+/// Match a robots.txt path pattern, supporting `*` wildcards and a trailing `$` anchor
+fn matches(pattern: &str, path: &str) -> bool {
+    let (pattern, anchored) = match pattern.strip_suffix('$') {
+        Some(p) => (p, true),
+        None => (pattern, false),
+    };
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    if !path.starts_with(first) {
+        return false;
+    }
+    let mut rest = &path[first.len()..];
+    let parts = parts.collect::<Vec<&str>>();
+    for (i, part) in parts.iter().enumerate() {
+        // The final piece of an anchored pattern must sit at the very end
+        if anchored && i == parts.len() - 1 {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(idx) => rest = &rest[idx + part.len()..],
+            None => return false,
+        }
+    }
+    !anchored || rest.is_empty()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_robots() {
+        let body = "\
+User-agent: *
+Disallow: /private/
+Allow: /private/calendar
+
+User-agent: BadBot
+User-agent: dalia-challenge
+Disallow: /en/program$
+Disallow: /*.pdf
+";
+        let ours = Robots::parse(body, "dalia-challenge/0.1 (+https://example.com)");
+        assert!(!ours.allowed("/en/program"));
+        assert!(ours.allowed("/en/program/page-2"));
+        assert!(!ours.allowed("/files/flyer.pdf"));
+        assert!(ours.allowed("/private/"));
+
+        let others = Robots::parse(body, "SomeoneElse/1.0");
+        assert!(!others.allowed("/private/stuff"));
+        assert!(others.allowed("/private/calendar"));
+        assert!(others.allowed("/en/program"));
+
+        // Our group allowing everything overrides the `*` group
+        let open = "User-agent: *\nDisallow: /\n\nUser-agent: dalia-challenge\nDisallow:\n";
+        assert!(Robots::parse(open, "dalia-challenge/0.1").allowed("/en/program"));
+
+        assert!(Robots::allow_all().allowed("/"));
+        assert!(!Robots::disallow_all().allowed("/en/calender"));
+    }
+}
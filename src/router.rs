@@ -1,50 +1,417 @@
+// router.rs
+// Matching requests to handlers, with middleware around them
+
 use super::*;
-use hyper::{Body, Method, Request};
-use log::{info, warn};
+use hyper::{Body, Method, Request, Response};
+use lazy_static::lazy_static;
+use log::{error, log, warn};
+use std::{convert::Infallible, future::Future, pin::Pin};
+use uuid::Uuid;
+
+/// Service entry point - turns any error escaping the router into an error page
+pub async fn handle(req: Request<Body>, state: SharedState) -> Result<Response<Body>, Infallible> {
+    let (method, path) = (req.method().clone(), req.uri().path().to_string());
+    match router(req, &state).await {
+        Ok(response) => Ok(response),
+        Err(e) => {
+            let request_id = RequestId(Uuid::new_v4().to_string());
+            Ok(error_response(&method, &path, Some(&request_id), e).await)
+        }
+    }
+}
+
+/// Log an error with its context and render the page the user sees for it
+async fn error_response(
+    method: &Method,
+    path: &str,
+    request_id: Option<&RequestId>,
+    e: AppError,
+) -> Response<Body> {
+    let status = e.status();
+    let request_id = request_id.map(|id| id.0.as_str());
+    log!(
+        e.log_level(),
+        "[{}] {} {} failed: {}",
+        request_id.unwrap_or("-"),
+        method,
+        path,
+        e
+    );
+    // Only server-side failures show the ID, to quote back at us
+    let page = match e {
+        AppError::NotFound(_) => four_oh_four().await,
+        _ if status.is_server_error() => error_page(status, e.user_message(), request_id).await,
+        _ => error_page(status, e.user_message(), None).await,
+    };
+    match page {
+        Ok(mut response) => {
+            if let AppError::Unauthorized(_) = e {
+                response.headers_mut().insert(
+                    hyper::header::WWW_AUTHENTICATE,
+                    hyper::header::HeaderValue::from_static("Bearer"),
+                );
+            }
+            response
+        }
+        Err(render_err) => {
+            error!("Could not render error page: {}", render_err);
+            let mut response = Response::new(Body::from(e.user_message().to_string()));
+            *response.status_mut() = status;
+            response
+        }
+    }
+}
+
+lazy_static! {
+    /// Every route the site serves
+    static ref ROUTES: Router = routes();
+}
 
 /// Top-level route handler
-pub async fn router(req: Request<Body>) -> HandlerResult {
-    let (method, path) = (req.method(), req.uri().path());
-    info!("{} {}", method, path);
-    match (method, path) {
-        (&Method::GET, "/")
-        | (&Method::POST, "/")
-        | (&Method::GET, "/index.html")
-        | (&Method::POST, "/index.html") => index(req).await,
-        (&Method::GET, "/main.css") => {
-            string_handler(include_str!("assets/main.css"), "text/css", None).await
-        }
-        (&Method::GET, "/app.js") => {
-            string_handler(
-                include_str!("assets/app.js"),
-                "application/javascript",
-                None,
+pub async fn router(req: Request<Body>, state: &AppState) -> HandlerResult {
+    ROUTES.dispatch(req, state).await
+}
+
+/// The site's routes and the middleware around them
+fn routes() -> Router {
+    let static_files = format!("{}{{*path}}", STATIC_PREFIX);
+    Router::default()
+        .with(access_log)
+        .with(record_metrics)
+        .with(compress)
+        .route("/", &[Method::GET, Method::POST], |req, state, _| {
+            Box::pin(index(req, state))
+        })
+        .route(
+            "/index.html",
+            &[Method::GET, Method::POST],
+            |req, state, _| Box::pin(index(req, state)),
+        )
+        .route("/refresh", &[Method::POST], |_, state, _| {
+            Box::pin(refresh_events(state))
+        })
+        .route("/metrics", &[Method::GET], |_, state, _| {
+            Box::pin(metrics(state))
+        })
+        .route("/healthz", &[Method::GET], |_, _, _| Box::pin(healthz()))
+        .route("/readyz", &[Method::GET], |_, state, _| {
+            Box::pin(readyz(state))
+        })
+        .route("/events/{id}.ics", &[Method::GET], |_, state, params| {
+            Box::pin(async move { event_calendar(state, event_id(params.get("id"))?).await })
+        })
+        .route("/events/{id}", &[Method::GET], |_, state, params| {
+            Box::pin(async move { event_page(state, event_id(params.get("id"))?).await })
+        })
+        .group(
+            "/admin",
+            Router::default().with(require_admin).with(no_store).route(
+                "/events/{id}/tags",
+                &[Method::GET, Method::PUT],
+                |req, state, params| {
+                    Box::pin(
+                        async move { event_tags(req, state, event_id(params.get("id"))?).await },
+                    )
+                },
+            ),
+        )
+        .route("/favicon.ico", &[Method::GET], |req, state, _| {
+            Box::pin(async move { static_file(&req, &state.opt.static_dir, "favicon.ico").await })
+        })
+        .route(&static_files, &[Method::GET], |req, state, params| {
+            Box::pin(
+                async move { static_file(&req, &state.opt.static_dir, params.get("path")).await },
             )
-            .await
-        }
-        (&Method::GET, "/manifest.json") => {
-            string_handler(include_str!("assets/manifest.json"), "text/json", None).await
-        }
-        (&Method::GET, "/robots.txt") => {
-            string_handler(include_str!("assets/robots.txt"), "text", None).await
-        }
-        (&Method::POST, "/refresh") => refresh_events().await,
-        (&Method::GET, path_str) => {
-            // Otherwise...
-            // is it an image?
-            if let Some(ext) = path_str.split('.').nth(1) {
-                match ext {
-                    "ico" | "svg" => image(path).await,
-                    _ => four_oh_four().await,
+        })
+        .route_if(
+            "/{file}",
+            |path| lookup_asset(path).is_some(),
+            &[Method::GET],
+            |req, _, _| {
+                Box::pin(async move {
+                    match lookup_asset(req.uri().path()) {
+                        Some((asset, fingerprinted)) => {
+                            asset_handler(&req, asset, fingerprinted).await
+                        }
+                        None => four_oh_four().await,
+                    }
+                })
+            },
+        )
+}
+
+/// A boxed handler or middleware future, borrowing the shared state
+pub type BoxFuture<'a> = Pin<Box<dyn Future<Output = HandlerResult> + Send + 'a>>;
+
+/// Handles requests for one route
+pub type Handler = for<'a> fn(Request<Body>, &'a AppState, Params) -> BoxFuture<'a>;
+
+/// Runs around a handler, calling `Next::run` to carry on down the chain
+/// Any error it returns is rendered as an error page for the middleware above it.
+pub type Middleware = for<'a> fn(Request<Body>, &'a AppState, Next) -> BoxFuture<'a>;
+
+/// Pattern of the route a request matched, even if not with its method
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedRoute(pub String);
+
+/// Values of a route's path parameters, by name
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Params(Vec<(String, String)>);
+
+impl Params {
+    /// The named parameter, or "" if the route has none of that name
+    pub fn get(&self, name: &str) -> &str {
+        self.0
+            .iter()
+            .find(|(key, _)| key == name)
+            .map_or("", |(_, value)| value.as_str())
+    }
+}
+
+/// One path pattern and its handlers
+struct Route {
+    pattern: String,
+    /// Further check on the path, for patterns too loose on their own
+    guard: Option<fn(&str) -> bool>,
+    handlers: Vec<(Method, Handler)>,
+    /// Run inside the router's own middleware
+    middleware: Vec<Middleware>,
+}
+
+/// Routes tried in the order they were added, with middleware run around every request
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    middleware: Vec<Middleware>,
+}
+
+impl Router {
+    /// Run `middleware` around every request, inside any added before it
+    pub fn with(mut self, middleware: Middleware) -> Self {
+        self.middleware.push(middleware);
+        self
+    }
+    /// Handle a path pattern with the given methods
+    /// See `path_params` for the pattern syntax.
+    pub fn route(self, pattern: &str, methods: &[Method], handler: Handler) -> Self {
+        self.add(pattern, None, methods, handler)
+    }
+    /// Handle a path pattern with the given methods, for paths `guard` accepts
+    pub fn route_if(
+        self,
+        pattern: &str,
+        guard: fn(&str) -> bool,
+        methods: &[Method],
+        handler: Handler,
+    ) -> Self {
+        self.add(pattern, Some(guard), methods, handler)
+    }
+    /// Mount another router's routes below `prefix`, each wrapped in its middleware
+    pub fn group(mut self, prefix: &str, group: Router) -> Self {
+        for mut route in group.routes {
+            route.pattern = format!("{}{}", prefix, route.pattern);
+            route.middleware = group
+                .middleware
+                .iter()
+                .chain(&route.middleware)
+                .cloned()
+                .collect();
+            self.routes.push(route);
+        }
+        self
+    }
+    /// Route a request through the middleware to its handler
+    /// Paths no route matches get a 404, and known paths requested with another method a 405.
+    pub fn dispatch<'a>(&'a self, mut req: Request<Body>, state: &'a AppState) -> BoxFuture<'a> {
+        let path = req.uri().path().to_string();
+        let path = path.as_str();
+        let mut allowed = Vec::new();
+        let mut endpoint = None;
+        let mut matched = None;
+        for route in &self.routes {
+            let params = match path_params(&route.pattern, path) {
+                Some(params) if route.guard.is_none_or(|guard| guard(path)) => params,
+                _ => continue,
+            };
+            matched.get_or_insert(route.pattern.as_str());
+            match route.handlers.iter().find(|(m, _)| m == req.method()) {
+                Some((_, handler)) => {
+                    let params = Params(
+                        param_names(&route.pattern)
+                            .zip(params)
+                            .map(|(name, value)| (name.to_string(), value.to_string()))
+                            .collect(),
+                    );
+                    endpoint = Some((Endpoint::Handler(*handler, params), &route.middleware));
+                    break;
                 }
-            } else {
-                four_oh_four().await
+                None => allowed.extend(route.handlers.iter().map(|(m, _)| m.clone())),
             }
         }
-        _ => {
-            // Not a configured route!
-            warn!("{}: 404!", path);
-            four_oh_four().await
+        let (endpoint, route_middleware) = match endpoint {
+            Some(found) => found,
+            None if allowed.is_empty() => (Endpoint::NotFound, &Vec::new()),
+            None => (Endpoint::NotAllowed(allowed), &Vec::new()),
+        };
+        if let Some(pattern) = matched {
+            req.extensions_mut()
+                .insert(MatchedRoute(pattern.to_string()));
         }
+        let middleware = self
+            .middleware
+            .iter()
+            .chain(route_middleware)
+            .cloned()
+            .collect::<Vec<Middleware>>();
+        Next {
+            middleware: middleware.into_iter(),
+            endpoint,
+        }
+        .run(req, state)
+    }
+    fn add(
+        mut self,
+        pattern: &str,
+        guard: Option<fn(&str) -> bool>,
+        methods: &[Method],
+        handler: Handler,
+    ) -> Self {
+        self.routes.push(Route {
+            pattern: pattern.into(),
+            guard,
+            handlers: methods.iter().map(|m| (m.clone(), handler)).collect(),
+            middleware: Vec::new(),
+        });
+        self
+    }
+}
+
+/// Where a request ends up once past the middleware
+enum Endpoint {
+    Handler(Handler, Params),
+    NotAllowed(Vec<Method>),
+    NotFound,
+}
+
+/// The rest of the middleware chain, and the endpoint at the end of it
+pub struct Next {
+    middleware: std::vec::IntoIter<Middleware>,
+    endpoint: Endpoint,
+}
+
+impl Next {
+    /// Pass the request on to the next middleware, or the endpoint
+    /// Errors come back already rendered as error pages.
+    pub fn run(mut self, req: Request<Body>, state: &AppState) -> BoxFuture<'_> {
+        let (method, path) = (req.method().clone(), req.uri().path().to_string());
+        let request_id = req.extensions().get::<RequestId>().cloned();
+        let future = match self.middleware.next() {
+            Some(middleware) => middleware(req, state, self),
+            None => match self.endpoint {
+                Endpoint::Handler(handler, params) => handler(req, state, params),
+                Endpoint::NotAllowed(allowed) => {
+                    Box::pin(async move { method_not_allowed(&allowed).await })
+                }
+                Endpoint::NotFound => Box::pin(async move {
+                    // Not a configured route!
+                    warn!("{}: 404!", req.uri().path());
+                    four_oh_four().await
+                }),
+            },
+        };
+        Box::pin(async move {
+            match future.await {
+                Ok(response) => Ok(response),
+                Err(e) => Ok(error_response(&method, &path, request_id.as_ref(), e).await),
+            }
+        })
+    }
+}
+
+/// Match a path against a pattern like "/events/{id}.ics", returning each parameter's value
+/// A parameter matches one non-empty segment, less any fixed text after it in the pattern,
+/// except a final "{*name}", which matches the rest of the path.
+pub fn path_params<'a>(pattern: &str, path: &'a str) -> Option<Vec<&'a str>> {
+    let mut rest = Some(path);
+    let mut ret = Vec::new();
+    for expected in pattern.split('/') {
+        let remaining = rest?;
+        if expected.starts_with("{*") {
+            ret.push(remaining);
+            rest = None;
+            continue;
+        }
+        let segment = match remaining.split_once('/') {
+            Some((segment, tail)) => {
+                rest = Some(tail);
+                segment
+            }
+            None => {
+                rest = None;
+                remaining
+            }
+        };
+        match expected.strip_prefix('{') {
+            Some(param) => {
+                let suffix = &param[param.find('}')? + 1..];
+                match segment.strip_suffix(suffix) {
+                    Some(value) if !value.is_empty() => ret.push(value),
+                    _ => return None,
+                }
+            }
+            None if expected == segment => {}
+            None => return None,
+        }
+    }
+    match rest {
+        None => Some(ret),
+        Some(_) => None,
+    }
+}
+
+/// Names of a pattern's parameters, in order
+fn param_names(pattern: &str) -> impl Iterator<Item = &str> {
+    pattern
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{'))
+        .filter_map(|param| param.split('}').next())
+        .map(|name| name.trim_start_matches('*'))
+}
+
+/// Parse an event ID from a path, treating anything else as a missing page
+fn event_id(param: &str) -> AppResult<i32> {
+    param
+        .parse::<i32>()
+        .map_err(|_| AppError::NotFound(format!("no event {:?}", param)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_path_params() {
+        assert_eq!(path_params("/events/{id}", "/events/7"), Some(vec!["7"]));
+        assert_eq!(
+            path_params("/events/{id}.ics", "/events/7.ics"),
+            Some(vec!["7"])
+        );
+        assert_eq!(
+            path_params("/admin/events/{id}/tags", "/admin/events/7/tags"),
+            Some(vec!["7"])
+        );
+        assert_eq!(path_params("/events/{id}.ics", "/events/7"), None);
+        assert_eq!(path_params("/events/{id}", "/events/"), None);
+        assert_eq!(path_params("/events/{id}", "/events/7/tags"), None);
+        assert_eq!(path_params("/events/{id}", "/venues/7"), None);
+        assert_eq!(
+            path_params("/images/{*path}", "/images/venues/logo.svg"),
+            Some(vec!["venues/logo.svg"])
+        );
+        assert_eq!(
+            param_names("/events/{id}/{*rest}").collect::<Vec<&str>>(),
+            vec!["id", "rest"]
+        );
     }
 }
@@ -1,3 +1,10 @@
+table! {
+    data_version (id) {
+        id -> Integer,
+        version -> Integer,
+    }
+}
+
 table! {
     events (id) {
         id -> Integer,
@@ -8,6 +15,30 @@ table! {
         event_date -> Text,
         event_end_date -> Nullable<Text>,
         source -> Text,
+        venue -> Nullable<Text>,
+        canonical_id -> Nullable<Integer>,
+        first_seen -> Nullable<Text>,
+        last_updated -> Nullable<Text>,
+    }
+}
+
+table! {
+    event_revisions (id) {
+        id -> Integer,
+        event_id -> Integer,
+        revised_dt -> Text,
+        field -> Text,
+        old_value -> Nullable<Text>,
+        new_value -> Nullable<Text>,
+    }
+}
+
+table! {
+    event_tags (event_id, tag) {
+        event_id -> Integer,
+        tag -> Text,
+        manual -> Bool,
+        removed -> Bool,
     }
 }
 
@@ -16,7 +47,49 @@ table! {
         id -> Integer,
         refresh_dt -> Text,
         total_added -> Integer,
+        sources_unchanged -> Integer,
     }
 }
 
-allow_tables_to_appear_in_same_query!(events, refreshes,);
+table! {
+    source_pages (url) {
+        url -> Text,
+        etag -> Nullable<Text>,
+        last_modified -> Nullable<Text>,
+        content_hash -> Text,
+        fetched_dt -> Text,
+    }
+}
+
+table! {
+    tags (name) {
+        name -> Text,
+    }
+}
+
+table! {
+    venues (name) {
+        name -> Text,
+        address -> Nullable<Text>,
+        district -> Nullable<Text>,
+        latitude -> Nullable<Double>,
+        longitude -> Nullable<Double>,
+        website -> Nullable<Text>,
+    }
+}
+
+joinable!(event_revisions -> events (event_id));
+joinable!(event_tags -> events (event_id));
+joinable!(event_tags -> tags (tag));
+joinable!(events -> venues (venue));
+
+allow_tables_to_appear_in_same_query!(
+    data_version,
+    event_revisions,
+    event_tags,
+    events,
+    refreshes,
+    source_pages,
+    tags,
+    venues,
+);
@@ -2,168 +2,389 @@
 
 use super::*;
 use chrono::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use log::debug;
 use select::{
     document::Document,
+    node::Node,
     predicate::{Class, Name, Predicate},
 };
-use std::fmt;
+use std::{fmt, sync::Arc, time::Instant};
+use url::Url;
 
 /// Types that implement Calendar can be used to populate the event DB table
-pub trait Calendar: Copy {
-    /// Scrape all the events on the given page and add them to the database
-    /// Returns number of events added
-    fn scrape_events(self, document: Document) -> AppResult<usize>;
+pub trait Calendar {
+    /// Parse all the events on the given page
+    fn parse_events(&self, page: &Page, config: &SourceConfig) -> AppResult<ParsedEvents>;
+    /// Compare the events on the given page with the stored ones, without writing anything
+    fn diff_events(
+        &self,
+        page: &Page,
+        config: &SourceConfig,
+        stored: &[Event],
+    ) -> AppResult<ScrapeDiff>;
+    /// Scrape all the events on the given page, adding new ones and updating changed ones
+    /// Returns how many events were parsed, added, updated and rejected
+    fn scrape_events(
+        &self,
+        page: &Page,
+        config: &SourceConfig,
+        state: &AppState,
+        conn: &SqliteConnection,
+    ) -> AppResult<SourceCounts> {
+        let stored = state.metrics.timed("all_events", || all_events(conn))?;
+        let diff = self.diff_events(page, config, &stored)?;
+        let parsed = diff.inserted.len() + diff.updated.len() + diff.unchanged.len();
+        let failed = diff.failed;
+        let written = diff.written_days();
+        let (added, updated) = diff.apply(conn, &state.tagger, &state.metrics)?;
+        Ok(SourceCounts {
+            parsed,
+            added,
+            updated,
+            failed,
+            written,
+        })
+    }
+}
+
+/// A source page ready to scrape
+#[derive(Debug)]
+pub struct Page {
+    /// Where the page was served from, after any redirects
+    pub url: Url,
+    pub document: Document,
+    /// The page as served, for sources that aren't HTML
+    pub body: String,
+}
+
+impl Page {
+    pub fn new(url: Url, html: &str) -> Self {
+        Self {
+            url,
+            document: Document::from(html),
+            body: html.into(),
+        }
+    }
+    /// Resolve a link on this page to an absolute URL
+    /// Only web links are accepted, since they end up in our pages' `href`s.
+    pub fn resolve(&self, href: &str) -> AppResult<String> {
+        let url = self
+            .url
+            .join(href.trim())
+            .map_err(|e| AppError::parse(format!("bad link {:?}: {}", href, e)))?;
+        if !is_web_url(&url) {
+            return Err(AppError::parse(format!("link {:?} isn't http(s)", href)));
+        }
+        Ok(url.to_string())
+    }
+}
+
+/// Whether a URL is http or https, and so safe to link to
+/// Other schemes such as `javascript:` would run in our pages.
+pub fn is_web_url(url: &Url) -> bool {
+    matches!(url.scheme(), "http" | "https")
+}
+
+/// Events read from a source page
+/// Parsers that can skip an unreadable item, rather than fail the whole page, count it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParsedEvents {
+    pub events: Vec<NewEvent>,
+    /// Items skipped because they couldn't be read as events
+    pub failed: usize,
+}
+
+/// What scraping one source's page did
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceCounts {
+    /// Events found on the page
+    pub parsed: usize,
+    /// Events inserted
+    pub added: usize,
+    /// Stored events changed
+    pub updated: usize,
+    /// Items on the page skipped as unreadable
+    pub failed: usize,
+    /// Days of the events added or changed
+    pub written: Vec<DayRange>,
+}
+
+/// What a scrape of several sources did
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ScrapeTotals {
+    /// Events inserted
+    pub added: usize,
+    /// Sources skipped because their page hadn't changed
+    pub unchanged: usize,
 }
 
 /// All the implemented event source calendars
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum EventSource {
     CoBerlin(bool),
     Berghain(bool),
+    /// A venue added in the config by URL, read from the schema.org JSON-LD in its page
+    JsonLd(Arc<str>, bool),
+    /// A venue's iCalendar feed added in the config
+    Ical(Arc<str>, bool),
+    /// A venue's RSS or Atom feed added in the config
+    Rss(Arc<str>, bool),
 }
 
 impl EventSource {
     // Associated methods
 
-    /// Get all the event sources to iterate over
-    pub fn all() -> &'static [EventSource] {
+    /// The sources with hand-written parsers, which are always available
+    pub fn builtin() -> Vec<EventSource> {
         use EventSource::*;
-        &[CoBerlin(true), Berghain(true)]
+        vec![CoBerlin(true), Berghain(true)]
     }
-    /// Scrape all the event sources, adding each new event found to the DB.  Returns number of events added
-    pub async fn scrape_all_events() -> AppResult<usize> {
-        let mut ret = 0;
-        for src in Self::all() {
-            let html = src.get_html().await?;
-            let document = Document::from(html.as_str());
-            ret += src.scrape_events(document)?;
+    /// A source added in the config under `name`, unless `kind` needs a built-in parser
+    pub fn configured(name: &str, kind: SourceKind) -> Option<Self> {
+        use EventSource::*;
+        match kind {
+            SourceKind::Builtin => None,
+            SourceKind::JsonLd => Some(JsonLd(name.into(), true)),
+            SourceKind::Ical => Some(Ical(name.into(), true)),
+            SourceKind::Rss => Some(Rss(name.into(), true)),
+        }
+    }
+    /// Scrape all the enabled event sources whose pages changed, adding each new event found to the DB
+    pub async fn scrape_all_events(state: &AppState) -> AppResult<ScrapeTotals> {
+        Self::scrape_sources(state, state.sources.enabled(), false).await
+    }
+    /// Scrape the given event sources, adding each new event found to the DB
+    /// Pages that haven't changed since the last scrape are skipped, unless `force` is set.
+    pub async fn scrape_sources<'a>(
+        state: &AppState,
+        sources: impl Iterator<Item = (&'a EventSource, &'a SourceConfig)>,
+        force: bool,
+    ) -> AppResult<ScrapeTotals> {
+        let mut ret = ScrapeTotals::default();
+        let mut written = Vec::new();
+        for (src, config) in sources {
+            let started = Instant::now();
+            let scraped = src.scrape_source(state, config, force).await;
+            let counts = match &scraped {
+                Ok(Some(counts)) => counts.clone(),
+                _ => SourceCounts::default(),
+            };
+            state
+                .metrics
+                .observe_scrape(src.as_str(), started.elapsed(), &counts);
+            match scraped? {
+                None => ret.unchanged += 1,
+                Some(counts) => {
+                    if counts.added + counts.updated > 0 {
+                        state.listing_cache.invalidate();
+                    }
+                    ret.added += counts.added;
+                    written.extend(counts.written);
+                }
+            }
+        }
+        // New or edited listings may duplicate ones from other sources
+        if !written.is_empty() && dedup_events_around(&*state.pool.get()?, &written)? > 0 {
+            state.listing_cache.invalidate();
         }
         Ok(ret)
     }
 
     // Instance methods
 
-    pub fn as_str(self) -> &'static str {
+    /// Scrape this source if its page changed, returning what it found and wrote
+    /// Returns None if the page was unchanged.
+    async fn scrape_source(
+        &self,
+        state: &AppState,
+        config: &SourceConfig,
+        force: bool,
+    ) -> AppResult<Option<SourceCounts>> {
+        let url = self.url_calendar(config);
+        let previous = if force {
+            None
+        } else {
+            source_page(&*state.pool.get()?, &url)?
+        };
+        let (page, source_page) = match state
+            .fetcher
+            .fetch_if_changed(self, &url, previous.as_ref())
+            .await?
+        {
+            Fetched::Changed(page, source_page) => (page, source_page),
+            Fetched::Unchanged(source_page) => {
+                debug!("{} is unchanged, skipping", url);
+                save_source_page(&*state.pool.get()?, &source_page)?;
+                return Ok(None);
+            }
+        };
+        let conn = state.pool.get()?;
+        let counts = self
+            .scrape_events(&page, config, state, &conn)
+            .map_err(|e| self.tag_error(e))?;
+        // Only remember the page once it's parsed, so a failed parse is retried next time
+        save_source_page(&conn, &source_page)?;
+        Ok(Some(counts))
+    }
+
+    pub fn as_str(&self) -> &str {
         use EventSource::*;
         match self {
             CoBerlin(_) => "CoBerlin",
             Berghain(_) => "Berghain",
+            JsonLd(name, _) | Ical(name, _) | Rss(name, _) => name,
         }
     }
     /// Check whether this source is enabled
-    pub fn enabled(self) -> bool {
+    pub fn enabled(&self) -> bool {
         use EventSource::*;
         match self {
-            CoBerlin(b) | Berghain(b) => b,
+            CoBerlin(b) | Berghain(b) | JsonLd(_, b) | Ical(_, b) | Rss(_, b) => *b,
         }
     }
-    /// Retrieve the current HTML from the source
-    pub async fn get_html(self) -> AppResult<String> {
-        let response = reqwest::get(&self.url_calendar()).await?;
-        Ok(response.text().await?)
+    /// Fetch and parse the HTML of the source's calendar page
+    pub async fn fetch_page(&self, fetcher: &Fetcher, config: &SourceConfig) -> AppResult<Page> {
+        fetcher.fetch(self, &self.url_calendar(config)).await
+    }
+    /// Name this source in a parse error, so we know whose markup changed
+    pub fn tag_error(&self, e: AppError) -> AppError {
+        match e {
+            AppError::Parse(e) => AppError::Parse(e.context(self.as_str().to_string())),
+            e => e,
+        }
     }
     /// Name for use in HTML markup
-    pub fn markup_name(self) -> String {
-        format!("source-{}", self.as_str().to_lowercase())
+    pub fn markup_name(&self) -> String {
+        let name = self
+            .as_str()
+            .chars()
+            .filter(|c| c.is_ascii_alphanumeric())
+            .collect::<String>();
+        format!("source-{}", name.to_lowercase())
     }
     /// Name for use in Display impl
-    pub fn pretty_name(self) -> &'static str {
+    pub fn pretty_name(&self) -> &str {
         use EventSource::*;
         match self {
             CoBerlin(_) => "C/O Berlin",
-            Berghain(_) => self.as_str(),
+            Berghain(_) | JsonLd(..) | Ical(..) | Rss(..) => self.as_str(),
         }
     }
-    /// Static string for the webpage URL
-    fn url_base(self) -> &'static str {
+    /// Venue for events whose parser didn't name one
+    pub fn default_venue(&self, config: &SourceConfig) -> Option<String> {
         use EventSource::*;
+        if config.venue.is_some() {
+            return config.venue.clone();
+        }
         match self {
-            CoBerlin(_) => "http://www.co-berlin.org",
-            Berghain(_) => "http://berghain.de",
+            CoBerlin(_) => Some("C/O Berlin".into()),
+            Berghain(_) => Some("Berghain".into()),
+            JsonLd(..) | Ical(..) | Rss(..) => None,
         }
     }
-    // Build a URL
-    pub fn url(self, uri: &str) -> String {
-        format!("{}/{}", self.url_base(), uri)
-    }
-    /// Static string for the webpage URL
-    fn url_calendar(self) -> String {
+    /// Tags for every event from this source
+    pub fn default_categories(&self, config: &SourceConfig) -> Vec<String> {
         use EventSource::*;
-        let uri = match self {
-            CoBerlin(_) => "en/calender",
-            Berghain(_) => "en/program",
+        if let Some(categories) = &config.categories {
+            return categories.iter().map(|c| normalize_tag(c)).collect();
+        }
+        let builtin: &[&str] = match self {
+            CoBerlin(_) => &["photography"],
+            Berghain(_) => &["club night"],
+            JsonLd(..) | Ical(..) | Rss(..) => &[],
         };
-        self.url(uri)
+        builtin.iter().map(|c| c.to_string()).collect()
+    }
+    /// Calendar page URL, unless overridden in the config
+    pub fn url_calendar(&self, config: &SourceConfig) -> String {
+        use EventSource::*;
+        if let Some(url) = &config.calendar_url {
+            return url.clone();
+        }
+        match self {
+            CoBerlin(_) => "https://www.co-berlin.org/en/calender".into(),
+            Berghain(_) => "https://berghain.de/en/program".into(),
+            // Config validation won't accept one of these without a URL
+            JsonLd(..) | Ical(..) | Rss(..) => String::new(),
+        }
     }
     /// Toggle from true to false or vice versa
-    pub fn toggle(self) -> Self {
+    pub fn toggle(&self) -> Self {
         use EventSource::*;
         match self {
             CoBerlin(b) => CoBerlin(!b),
             Berghain(b) => Berghain(!b),
+            JsonLd(name, b) => JsonLd(name.clone(), !b),
+            Ical(name, b) => Ical(name.clone(), !b),
+            Rss(name, b) => Rss(name.clone(), !b),
         }
     }
 }
 
 impl Calendar for EventSource {
-    fn scrape_events(self, document: Document) -> AppResult<usize> {
-        // get all current events to search for matches
-        let conn = DB_POOL.get()?;
-        let all_events = all_events(&conn)?;
-
+    fn diff_events(
+        &self,
+        page: &Page,
+        config: &SourceConfig,
+        stored: &[Event],
+    ) -> AppResult<ScrapeDiff> {
+        Ok(ScrapeDiff::new(
+            self,
+            stored,
+            self.parse_events(page, config)?,
+        ))
+    }
+    fn parse_events(&self, page: &Page, config: &SourceConfig) -> AppResult<ParsedEvents> {
         // Iter through document
         use EventSource::*;
-        let mut ret = 0;
+        let document = &page.document;
+        let mut ret = ParsedEvents::default();
         match self {
+            JsonLd(..) => ret = parse_json_ld_events(page, self)?,
+            Ical(..) => ret = parse_ical_events(page, self, &config.fields)?,
+            Rss(..) => ret = parse_rss_events(page, self, &config.fields)?,
             CoBerlin(_) => {
                 for node in
                     document.find(Class("seite-c-single").descendant(Class("calender-text")))
                 {
-                    let href = self.url(node.find(Name("a")).next().unwrap().attr("href").unwrap());
+                    let href = page.resolve(attr(find_first(node, Name("a"), "link")?, "href")?)?;
                     let (event_date, event_end_date) = {
-                        let date = node
-                            .find(Class("article-over-title"))
-                            .next()
-                            .unwrap()
-                            .find(Class("article-date"))
-                            .next()
-                            .unwrap();
+                        let date = find_first(
+                            find_first(node, Class("article-over-title"), "over-title")?,
+                            Class("article-date"),
+                            "date",
+                        )?;
                         // range or single date?
                         match date.find(Class("date-display-range")).next() {
                             Some(div) => {
                                 let begin =
-                                    div.find(Class("date-display-start")).next().unwrap().text();
+                                    find_first(div, Class("date-display-start"), "start date")?
+                                        .text();
                                 let end =
-                                    div.find(Class("date-display-end")).next().unwrap().text();
-                                let begin_dt = NaiveDate::parse_from_str(&begin, "%d/%m/%y")
-                                    .expect("Should parse date");
-                                let end_dt = NaiveDate::parse_from_str(&end, "%d/%m/%y")
-                                    .expect("Should parse date");
+                                    find_first(div, Class("date-display-end"), "end date")?.text();
+                                let begin_dt = parse_date(&begin, "%d/%m/%y")?;
+                                let end_dt = parse_date(&end, "%d/%m/%y")?;
 
                                 (begin_dt.to_string(), Some(end_dt.to_string()))
                             }
                             None => {
-                                let single_date = NaiveDate::parse_from_str(
-                                    &date
-                                        .find(Class("date-display-single"))
-                                        .next()
-                                        .unwrap()
-                                        .text(),
+                                let single_date = parse_date(
+                                    &find_first(date, Class("date-display-single"), "date")?.text(),
                                     "%d/%m/%y",
-                                )
-                                .expect("Should parse date");
+                                )?;
                                 (single_date.to_string(), None)
                             }
                         }
                     };
-                    let title = node.find(Class("article-title")).next().unwrap().text();
-                    let subtitle = match node.find(Class("article-subtitle")).next() {
-                        Some(s) => Some(s.text()),
-                        None => None,
-                    };
-                    let synopsis = node.find(Class("article-text")).next().unwrap().text();
+                    let title = find_first(node, Class("article-title"), "title")?.text();
+                    let subtitle = node
+                        .find(Class("article-subtitle"))
+                        .next()
+                        .map(|s| s.text());
+                    let synopsis = find_first(node, Class("article-text"), "synopsis")?.text();
 
-                    let new_event = NewEvent::new(
+                    ret.events.push(NewEvent::new(
                         &title,
                         subtitle,
                         &href,
@@ -171,35 +392,26 @@ impl Calendar for EventSource {
                         &event_date,
                         event_end_date,
                         CoBerlin(true),
-                    );
-
-                    // Only add if it's a new event
-                    let matches: Vec<Event> = all_events
-                        .iter()
-                        .filter(|el| **el == new_event)
-                        .map(|el| el.to_owned())
-                        .collect();
-
-                    if matches.is_empty() {
-                        ret += create_event(&conn, new_event)?;
-                    }
+                    ));
                 }
             }
             Berghain(_) => {
                 for node in document.find(Class("upcoming-event")) {
-                    let href = self.url(node.attr("href").unwrap());
+                    let href = page.resolve(attr(node, "href")?)?;
 
                     let event_date = {
-                        let mut date_node = node.find(Name("p"));
-                        let mut node_text = date_node.next().unwrap().text();
+                        let mut node_text = find_first(node, Name("p"), "date")?.text();
                         node_text.retain(|c| c != '\n' && c != ' ');
-                        let dt = NaiveDateTime::parse_from_str(&node_text, "%A%d.%m.%Ystart%R")?;
+                        let dt = NaiveDateTime::parse_from_str(&node_text, "%A%d.%m.%Ystart%R")
+                            .map_err(|e| {
+                                AppError::parse(format!("bad date {:?}: {}", node_text, e))
+                            })?;
 
                         dt.to_string()
                     };
 
-                    let title = node.find(Name("h2")).next().unwrap().text();
-                    let subtitle = node.find(Name("h3")).next().unwrap().text();
+                    let title = find_first(node, Name("h2"), "title")?.text();
+                    let subtitle = find_first(node, Name("h3"), "subtitle")?.text();
 
                     let synopsis = {
                         let mut ret = String::new();
@@ -209,33 +421,101 @@ impl Calendar for EventSource {
                         ret
                     };
 
-                    let new_event = NewEvent::new(
-                        &title,
-                        Some(subtitle),
-                        &href,
-                        &synopsis,
-                        &event_date,
-                        None,
-                        Berghain(true),
-                    );
-
-                    // Only add if it's a new event
-                    let matches: Vec<Event> = all_events
-                        .iter()
-                        .filter(|el| **el == new_event)
-                        .map(|el| el.to_owned())
-                        .collect();
-
-                    if matches.is_empty() {
-                        ret += create_event(&conn, new_event)?;
-                    }
+                    // Nights held only upstairs are at Panorama Bar
+                    let venue = Some("Panorama Bar".to_string())
+                        .filter(|_| subtitle.trim().eq_ignore_ascii_case("Panorama Bar"));
+
+                    ret.events.push(NewEvent {
+                        venue,
+                        ..NewEvent::new(
+                            &title,
+                            Some(subtitle),
+                            &href,
+                            &synopsis,
+                            &event_date,
+                            None,
+                            Berghain(true),
+                        )
+                    });
                 }
             }
         }
+        for event in ret.events.iter_mut().filter(|e| e.venue.is_none()) {
+            event.venue = self.default_venue(config);
+        }
         Ok(ret)
     }
 }
 
+/// The event sources this instance knows about, with their settings
+/// Built-in sources come first, then any sources added in the config, by name.
+#[derive(Debug, Clone)]
+pub struct SourceRegistry {
+    sources: Vec<(EventSource, SourceConfig)>,
+}
+
+impl SourceRegistry {
+    pub fn new(opt: &Opt) -> Self {
+        let mut sources = EventSource::builtin()
+            .into_iter()
+            .map(|source| {
+                let config = opt.source(&source);
+                (source, config)
+            })
+            .collect::<Vec<(EventSource, SourceConfig)>>();
+        let mut configured = opt
+            .sources
+            .iter()
+            .filter_map(|(name, config)| {
+                EventSource::configured(name, config.kind).map(|source| (source, config.clone()))
+            })
+            .collect::<Vec<(EventSource, SourceConfig)>>();
+        configured.sort_by(|(a, _), (b, _)| a.as_str().cmp(b.as_str()));
+        sources.extend(configured);
+        Self { sources }
+    }
+    /// Every source, enabled or not, for offering as filters
+    pub fn all(&self) -> Vec<EventSource> {
+        self.sources
+            .iter()
+            .map(|(source, _)| source.clone())
+            .collect()
+    }
+    /// Sources that refreshes should scrape
+    pub fn enabled(&self) -> impl Iterator<Item = (&EventSource, &SourceConfig)> {
+        self.sources
+            .iter()
+            .filter(|(_, config)| config.enabled)
+            .map(|(source, config)| (source, config))
+    }
+    /// A single source by name, ignoring case, whether or not it's enabled
+    pub fn get(&self, name: &str) -> Option<(&EventSource, &SourceConfig)> {
+        self.sources
+            .iter()
+            .find(|(s, _)| s.as_str().eq_ignore_ascii_case(name))
+            .map(|(source, config)| (source, config))
+    }
+}
+
+/// First descendant of `node` matching `predicate`, or a parse error naming what was missing
+fn find_first<'a, P: Predicate>(node: Node<'a>, predicate: P, what: &str) -> AppResult<Node<'a>> {
+    node.find(predicate)
+        .next()
+        .ok_or_else(|| AppError::parse(format!("no {} found in event markup", what)))
+}
+
+/// Attribute of `node`, or a parse error naming it
+fn attr<'a>(node: Node<'a>, name: &str) -> AppResult<&'a str> {
+    node.attr(name)
+        .ok_or_else(|| AppError::parse(format!("event markup missing {} attribute", name)))
+}
+
+/// Parse a date from the page, or a parse error quoting it
+fn parse_date(s: &str, fmt: &str) -> AppResult<NaiveDate> {
+    NaiveDate::parse_from_str(s, fmt)
+        .map_err(|e| AppError::parse(format!("bad date {:?}: {}", s, e)))
+}
+
 impl fmt::Display for EventSource {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.pretty_name())
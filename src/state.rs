@@ -0,0 +1,37 @@
+// state.rs
+// Shared application state handed to every request
+
+use super::*;
+use std::sync::Arc;
+
+/// Everything a handler needs, built once in `main`
+pub struct AppState {
+    pub opt: Opt,
+    pub pool: Pool,
+    /// Retrieves event source pages, sharing its connection pool across scrapes
+    pub fetcher: Fetcher,
+    pub listing_cache: ListingCache,
+    pub sources: SourceRegistry,
+    /// Tags events as they're added or change
+    pub tagger: Tagger,
+    /// Everything recorded for `/metrics` since startup
+    pub metrics: Metrics,
+}
+
+/// Handle to the state shared between connections
+pub type SharedState = Arc<AppState>;
+
+impl AppState {
+    pub fn new(opt: Opt, pool: Pool) -> AppResult<Self> {
+        let sources = SourceRegistry::new(&opt);
+        Ok(Self {
+            fetcher: Fetcher::new(&opt)?,
+            listing_cache: ListingCache::default(),
+            tagger: Tagger::new(&opt, &sources),
+            metrics: Metrics::default(),
+            sources,
+            opt,
+            pool,
+        })
+    }
+}
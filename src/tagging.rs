@@ -0,0 +1,116 @@
+// tagging.rs
+// Categorising events as they're ingested
+
+use super::*;
+use diesel::sqlite::SqliteConnection;
+use std::collections::HashMap;
+
+/// Works out an event's tags from its source and the keyword rules
+#[derive(Debug, Clone, Default)]
+pub struct Tagger {
+    /// (tag, lower-cased keywords), sorted by tag
+    rules: Vec<(String, Vec<String>)>,
+    /// Default tags by source name
+    defaults: HashMap<String, Vec<String>>,
+}
+
+impl Tagger {
+    pub fn new(opt: &Opt, sources: &SourceRegistry) -> Self {
+        let mut rules = opt
+            .tag_rules
+            .iter()
+            .map(|(tag, keywords)| {
+                let keywords = keywords.iter().map(|k| k.trim().to_lowercase()).collect();
+                (normalize_tag(tag), keywords)
+            })
+            .collect::<Vec<(String, Vec<String>)>>();
+        rules.sort();
+        let defaults = sources
+            .all()
+            .into_iter()
+            .filter_map(|source| {
+                let (_, config) = sources.get(source.as_str())?;
+                let tags = source.default_categories(config);
+                Some((source.as_str().to_string(), tags))
+            })
+            .collect();
+        Self { rules, defaults }
+    }
+    /// Every tag the event should get: its source's, then any whose keywords it mentions
+    pub fn tags(&self, event: &NewEvent) -> Vec<String> {
+        let text = format!(
+            "{}\n{}\n{}",
+            event.title,
+            event.subtitle.as_deref().unwrap_or_default(),
+            event.synopsis
+        )
+        .to_lowercase();
+        let mut ret = self
+            .defaults
+            .get(&event.source)
+            .cloned()
+            .unwrap_or_default();
+        for (tag, keywords) in &self.rules {
+            if keywords.iter().any(|k| contains_word(&text, k)) {
+                ret.push(tag.clone());
+            }
+        }
+        ret.sort();
+        ret.dedup();
+        ret
+    }
+    /// Re-tag a stored event, keeping any tags set by hand
+    pub fn tag(&self, conn: &SqliteConnection, event_id: i32, event: &NewEvent) -> AppResult<()> {
+        set_auto_tags(conn, event_id, &self.tags(event))
+    }
+}
+
+/// Tags are compared lower-cased and trimmed, so "Club Night " and "club night" are one
+pub fn normalize_tag(tag: &str) -> String {
+    tag.trim().to_lowercase()
+}
+
+/// Whether `word` appears in `text` on its own, rather than inside a longer word
+fn contains_word(text: &str, word: &str) -> bool {
+    if word.is_empty() {
+        return false;
+    }
+    text.match_indices(word).any(|(i, _)| {
+        let before = text[..i].chars().next_back();
+        let after = text[i + word.len()..].chars().next();
+        !before.is_some_and(char::is_alphanumeric) && !after.is_some_and(char::is_alphanumeric)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_tags() {
+        let mut opt = Opt::defaults().unwrap();
+        opt.tag_rules = vec![
+            ("Talk".to_string(), vec!["talk".to_string()]),
+            ("tour".to_string(), vec!["Guided Tour".to_string()]),
+        ]
+        .into_iter()
+        .collect();
+        opt.sources.get_mut("Berghain").unwrap().categories = Some(vec!["Club Night".into()]);
+        let tagger = Tagger::new(&opt, &SourceRegistry::new(&opt));
+
+        let event =
+            |title: &str, source| NewEvent::new(title, None, "#", "", "2020-03-01", None, source);
+        assert_eq!(
+            tagger.tags(&event(
+                "Artist Talk and guided tour",
+                EventSource::CoBerlin(true)
+            )),
+            vec!["photography", "talk", "tour"]
+        );
+        assert_eq!(
+            tagger.tags(&event("Talkback Klubnacht", EventSource::Berghain(true))),
+            vec!["club night"]
+        );
+    }
+}
@@ -3,6 +3,8 @@
 
 use super::*;
 use askama::Template;
+use hyper::StatusCode;
+use url::Url;
 
 #[derive(Default, Template)]
 #[template(path = "skel.html")]
@@ -12,14 +14,50 @@ pub struct SkelTemplate {}
 #[template(path = "404.html")]
 pub struct FourOhFourTemplate {}
 
+#[derive(Template)]
+#[template(path = "error.html")]
+pub struct ErrorTemplate<'a> {
+    status: u16,
+    reason: &'a str,
+    message: &'a str,
+    request_id: Option<&'a str>,
+}
+
+impl<'a> ErrorTemplate<'a> {
+    pub fn new(status: StatusCode, message: &'a str, request_id: Option<&'a str>) -> Self {
+        Self {
+            status: status.as_u16(),
+            reason: status.canonical_reason().unwrap_or("Error"),
+            message,
+            request_id,
+        }
+    }
+}
+
+/// An event as shown in the listing
+pub struct ListedEvent {
+    pub event: Event,
+    pub tags: Vec<String>,
+    /// (source name, link) for each other source listing the same event
+    pub also_listed: Vec<(String, String)>,
+}
+
 #[derive(Template)]
 #[template(path = "index.html")]
 pub struct IndexTemplate<'a> {
     begin_date: &'a str,
     end_date: &'a str,
-    events: Vec<Event>,
+    events: Vec<ListedEvent>,
     title_like: &'a str,
     sources: &'a [EventSource],
+    /// Chosen venue and district, or "" for any
+    venue: &'a str,
+    district: &'a str,
+    venues: Vec<Venue>,
+    districts: Vec<String>,
+    /// Chosen category, or "" for any
+    category: &'a str,
+    categories: Vec<String>,
     last_refresh: &'a str,
 }
 
@@ -27,18 +65,72 @@ impl<'a> IndexTemplate<'a> {
     pub fn new(
         begin_date: &'a str,
         end_date: &'a str,
-        events: Vec<Event>,
-        title_like: &'a str,
-        sources: &'a [EventSource],
+        events: Vec<ListedEvent>,
+        query: &'a ListingQuery,
+        venues: Vec<Venue>,
+        categories: Vec<String>,
         last_refresh: &'a str,
     ) -> Self {
+        let mut districts = venues
+            .iter()
+            .filter_map(|v| v.district.clone())
+            .collect::<Vec<String>>();
+        districts.sort();
+        districts.dedup();
+        let title_like = query.title_like.as_str();
         Self {
             begin_date,
             end_date,
             events,
             title_like: if title_like == "%" { "" } else { title_like },
-            sources,
+            sources: &query.sources,
+            venue: query.venues.first().map_or("", String::as_str),
+            district: query.districts.first().map_or("", String::as_str),
+            venues,
+            districts,
+            category: query.categories.first().map_or("", String::as_str),
+            categories,
             last_refresh,
         }
     }
 }
+
+#[derive(Template)]
+#[template(path = "event.html")]
+pub struct EventTemplate<'a> {
+    event: &'a Event,
+    /// Display name of the source it was scraped from
+    source: String,
+    venue: Option<Venue>,
+    tags: Vec<String>,
+    /// Listings of the same event by other sources
+    duplicates: Vec<Event>,
+    revisions: Vec<Revision>,
+}
+
+impl<'a> EventTemplate<'a> {
+    pub fn new(
+        event: &'a Event,
+        source: String,
+        venue: Option<Venue>,
+        tags: Vec<String>,
+        duplicates: Vec<Event>,
+        revisions: Vec<Revision>,
+    ) -> Self {
+        // Only link to websites we know are web pages
+        let venue = venue.map(|venue| Venue {
+            website: venue
+                .website
+                .filter(|w| Url::parse(w).is_ok_and(|url| is_web_url(&url))),
+            ..venue
+        });
+        Self {
+            event,
+            source,
+            venue,
+            tags,
+            duplicates,
+            revisions,
+        }
+    }
+}
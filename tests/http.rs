@@ -0,0 +1,1007 @@
+// http.rs
+// In-process tests of the whole HTTP surface, against a temp database and mock event sources
+
+use dalia_challenge::*;
+use diesel::RunQueryDsl;
+use flate2::read::ZlibDecoder;
+use hyper::{
+    header,
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use pretty_assertions::assert_eq;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    io::Read,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+use tempfile::TempDir;
+
+/// A service wired to its own database, which is deleted on drop
+struct Harness {
+    state: SharedState,
+    _dir: TempDir,
+}
+
+impl Harness {
+    /// Fresh service, with each source pointed at the given calendar URL
+    fn new(source_urls: &[(&str, String)]) -> Self {
+        Self::with_opt(|opt| {
+            for (name, url) in source_urls {
+                opt.sources.get_mut(*name).unwrap().calendar_url = Some(url.clone());
+            }
+        })
+    }
+    /// Fresh service, with any settings changed by `configure`
+    fn with_opt(configure: impl FnOnce(&mut Opt)) -> Self {
+        let dir = tempfile::tempdir().expect("Should create temp dir");
+        let mut opt = Opt::defaults().expect("Should load default config");
+        opt.database_url = dir.path().join("test.sqlite").to_string_lossy().into();
+        opt.static_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("images");
+        opt.fetch_delay_ms = 0;
+        configure(&mut opt);
+        let pool = establish_and_run_migrations(&opt.database_url, 2).expect("Should create DB");
+        let state = AppState::new(opt, pool).expect("Should build state");
+        Self {
+            state: Arc::new(state),
+            _dir: dir,
+        }
+    }
+    /// Insert some events straight into the database
+    fn seed(&self) {
+        let conn = self.state.pool.get().unwrap();
+        for (title, date, source) in &[
+            (
+                "Photo Exhibition",
+                "2020-02-17",
+                EventSource::CoBerlin(true),
+            ),
+            ("Curator Tour", "2020-03-01", EventSource::CoBerlin(true)),
+            (
+                "Klubnacht",
+                "2020-02-21 23:59:00",
+                EventSource::Berghain(true),
+            ),
+        ] {
+            let event = NewEvent::new(title, None, "#", "Synopsis", date, None, source.clone());
+            create_event(&conn, event).unwrap();
+        }
+    }
+    async fn request(&self, req: Request<Body>) -> Response<Body> {
+        handle(req, self.state.clone()).await.unwrap()
+    }
+    async fn get(&self, uri: &str) -> Response<Body> {
+        self.request(Request::get(uri).body(Body::empty()).unwrap())
+            .await
+    }
+    async fn post_form(&self, uri: &str, form: &str) -> Response<Body> {
+        self.request(
+            Request::post(uri)
+                .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+                .body(Body::from(form.to_string()))
+                .unwrap(),
+        )
+        .await
+    }
+}
+
+/// Read a whole body, inflating it if it was sent compressed
+async fn body_text(response: Response<Body>) -> String {
+    let deflated = response
+        .headers()
+        .get(header::CONTENT_ENCODING)
+        .is_some_and(|v| v == "deflate");
+    let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+    if deflated {
+        let mut text = String::new();
+        ZlibDecoder::new(bytes.as_ref())
+            .read_to_string(&mut text)
+            .unwrap();
+        text
+    } else {
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+}
+
+fn header_str(response: &Response<Body>, name: header::HeaderName) -> &str {
+    response
+        .headers()
+        .get(name)
+        .map(|v| v.to_str().unwrap())
+        .unwrap_or_default()
+}
+
+fn header_str_req(req: &Request<Body>, name: header::HeaderName) -> &str {
+    req.headers()
+        .get(name)
+        .map(|v| v.to_str().unwrap())
+        .unwrap_or_default()
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = status;
+    response
+}
+
+/// Serve the fixture calendars on an ephemeral port, returning its address
+/// `/old/coberlin` redirects to `/coberlin`, which supports If-None-Match, `/private` is disallowed by robots.txt, `/busy` asks for a one second back-off the
+/// first time, and anything without our User-Agent is refused.
+async fn mock_sources() -> SocketAddr {
+    let busy = Arc::new(AtomicBool::new(true));
+    let make_svc = make_service_fn(move |_conn| {
+        let busy = busy.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let busy = busy.clone();
+                async move {
+                    let polite =
+                        header_str_req(&req, header::USER_AGENT).starts_with("dalia-challenge/");
+                    let page = match req.uri().path() {
+                        _ if !polite => return Ok(status_response(StatusCode::FORBIDDEN)),
+                        "/robots.txt" => "User-agent: *\nDisallow: /private\n",
+                        "/old/coberlin" => {
+                            let mut response = status_response(StatusCode::MOVED_PERMANENTLY);
+                            response
+                                .headers_mut()
+                                .insert(header::LOCATION, "/coberlin".parse().unwrap());
+                            return Ok(response);
+                        }
+                        "/coberlin" if header_str_req(&req, header::IF_NONE_MATCH) == "\"v1\"" => {
+                            return Ok(status_response(StatusCode::NOT_MODIFIED));
+                        }
+                        "/coberlin" => {
+                            let mut response =
+                                Response::new(Body::from(include_str!("fixtures/coberlin.html")));
+                            response
+                                .headers_mut()
+                                .insert(header::ETAG, "\"v1\"".parse().unwrap());
+                            return Ok(response);
+                        }
+                        "/berghain" | "/private" => include_str!("fixtures/berghain.html"),
+                        "/venue" => include_str!("fixtures/venue.html"),
+                        "/cinema.ics" => include_str!("fixtures/cinema.ics"),
+                        "/busy" if busy.swap(false, Ordering::SeqCst) => {
+                            let mut response = status_response(StatusCode::SERVICE_UNAVAILABLE);
+                            response
+                                .headers_mut()
+                                .insert(header::RETRY_AFTER, "1".parse().unwrap());
+                            return Ok(response);
+                        }
+                        "/busy" => include_str!("fixtures/berghain.html"),
+                        _ => "",
+                    };
+                    Ok::<_, Infallible>(Response::new(Body::from(page)))
+                }
+            }))
+        }
+    });
+    let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_svc);
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+#[tokio::test]
+async fn test_index_lists_events() {
+    let harness = Harness::new(&[]);
+    harness.seed();
+
+    let response = harness.get("/").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header_str(&response, header::CONTENT_TYPE), "text/html");
+    assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(header_str(&response, header::VARY), "Accept-Encoding");
+    assert_eq!(header_str(&response, "x-cache".parse().unwrap()), "MISS");
+    let etag = header_str(&response, header::ETAG).to_string();
+    let html = body_text(response).await;
+    assert!(html.contains("Total found: 3"));
+    assert!(html.contains("Photo Exhibition"));
+    assert!(html.contains("Klubnacht"));
+
+    // Second load comes from the cache, compressed for clients that accept it,
+    // and a matching ETag gets a 304
+    let response = harness
+        .request(
+            Request::get("/")
+                .header(header::ACCEPT_ENCODING, "deflate")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(header_str(&response, "x-cache".parse().unwrap()), "HIT");
+    assert_eq!(header_str(&response, header::CONTENT_ENCODING), "deflate");
+    assert!(body_text(response).await.contains("Total found: 3"));
+    let response = harness
+        .request(
+            Request::get("/")
+                .header(header::IF_NONE_MATCH, &etag)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    // Changes that record no refresh still change the validators
+    let cutoff = chrono::NaiveDate::from_ymd_opt(2020, 2, 18).unwrap();
+    delete_events_before(&harness.state.pool.get().unwrap(), cutoff).unwrap();
+    harness.state.listing_cache.invalidate();
+    let response = harness
+        .request(
+            Request::get("/")
+                .header(header::IF_NONE_MATCH, &etag)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(header_str(&response, header::ETAG), etag);
+    assert!(body_text(response).await.contains("Total found: 2"));
+}
+
+#[tokio::test]
+async fn test_index_sees_external_writes() {
+    let harness = Harness::new(&[]);
+    let html = body_text(harness.get("/").await).await;
+    assert!(html.contains("Total found: 0"));
+
+    // Another process, such as the scrape subcommand, writing straight to the database
+    harness.seed();
+    let response = harness.get("/").await;
+    assert_eq!(header_str(&response, "x-cache".parse().unwrap()), "HIT");
+    bump_data_version(&harness.state.pool.get().unwrap()).unwrap();
+    let response = harness.get("/").await;
+    assert_eq!(header_str(&response, "x-cache".parse().unwrap()), "MISS");
+    assert!(body_text(response).await.contains("Total found: 3"));
+}
+
+#[tokio::test]
+async fn test_index_filters() {
+    let harness = Harness::new(&[]);
+    harness.seed();
+
+    let html = body_text(harness.post_form("/", "source-berghain=on").await).await;
+    assert!(html.contains("Total found: 1"));
+    assert!(html.contains("Klubnacht"));
+
+    let html = body_text(harness.post_form("/", "title=tour").await).await;
+    assert!(html.contains("Total found: 1"));
+    assert!(html.contains("Curator Tour"));
+
+    let html = body_text(
+        harness
+            .post_form("/", "startdate=2020-02-18&enddate=2020-02-29")
+            .await,
+    )
+    .await;
+    assert!(html.contains("Total found: 1"));
+    assert!(html.contains("Klubnacht"));
+
+    let response = harness.post_form("/", "startdate=tomorrow").await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert!(body_text(response).await.contains("startdate"));
+}
+
+#[tokio::test]
+async fn test_event_page() {
+    let harness = Harness::new(&[]);
+    harness.seed();
+    {
+        let conn = harness.state.pool.get().unwrap();
+        let mut event = NewEvent::new(
+            "Photo Exhibition",
+            Some("Opening".into()),
+            "#",
+            "Synopsis",
+            "2020-02-17",
+            None,
+            EventSource::CoBerlin(true),
+        );
+        event.venue = Some("C/O Berlin".into());
+        let changes = vec![FieldChange {
+            field: "subtitle",
+            old: None,
+            new: Some("Opening".into()),
+        }];
+        update_event(&conn, 1, &event, &changes).unwrap();
+    }
+
+    let html = body_text(harness.get("/").await).await;
+    assert!(html.contains("href=\"/events/1\""));
+
+    let response = harness.get("/events/1").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let html = body_text(response).await;
+    assert!(html.contains("Photo Exhibition"));
+    assert!(html.contains("Opening"));
+    assert!(html.contains("Charlottenburg"));
+    assert!(html.contains("subtitle changed from"));
+    assert!(html.contains("/events/1.ics"));
+    assert!(html.contains(">website</a>"));
+
+    // Only web links are rendered, whatever got into the venues table
+    diesel::sql_query(
+        "UPDATE venues SET website = 'javascript:alert(1)' WHERE name = 'C/O Berlin'",
+    )
+    .execute(&*harness.state.pool.get().unwrap())
+    .unwrap();
+    let html = body_text(harness.get("/events/1").await).await;
+    assert!(!html.contains("javascript:"));
+    assert!(!html.contains(">website</a>"));
+
+    let response = harness.get("/events/1.ics").await;
+    assert_eq!(header_str(&response, header::CONTENT_TYPE), "text/calendar");
+    let ics = body_text(response).await;
+    assert!(ics.contains("SUMMARY:Photo Exhibition"));
+    assert_eq!(ics.matches("BEGIN:VEVENT").count(), 1);
+
+    for path in &["/events/99", "/events/abc", "/events/99.ics"] {
+        assert_eq!(harness.get(path).await.status(), StatusCode::NOT_FOUND);
+    }
+    let response = harness.request(Request::post("/events/1").body(Body::empty()).unwrap());
+    assert_eq!(response.await.status(), StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn test_scrape_updates_drop_cached_listing() {
+    let addr = mock_sources().await;
+    let harness = Harness::new(&[("Berghain", format!("http://{}/berghain", addr))]);
+    let berghain = || harness.state.sources.get("Berghain").into_iter();
+    EventSource::scrape_sources(&harness.state, berghain(), false)
+        .await
+        .unwrap();
+    harness.get("/").await;
+    let response = harness.get("/").await;
+    assert_eq!(header_str(&response, "x-cache".parse().unwrap()), "HIT");
+
+    // The stored copy drifts from the page, so scraping again only updates it
+    diesel::sql_query("UPDATE events SET synopsis = 'Stale'")
+        .execute(&*harness.state.pool.get().unwrap())
+        .unwrap();
+    let totals = EventSource::scrape_sources(&harness.state, berghain(), true)
+        .await
+        .unwrap();
+    assert_eq!(totals.added, 0);
+    let response = harness.get("/").await;
+    assert_eq!(header_str(&response, "x-cache".parse().unwrap()), "MISS");
+}
+
+#[tokio::test]
+async fn test_refresh_scrapes_sources() {
+    let addr = mock_sources().await;
+    let harness = Harness::new(&[
+        ("CoBerlin", format!("http://{}/old/coberlin", addr)),
+        ("Berghain", format!("http://{}/berghain", addr)),
+    ]);
+
+    let response = harness.request(Request::post("/refresh").body(Body::empty()).unwrap());
+    assert_eq!(response.await.status(), StatusCode::OK);
+    let refresh = latest_refresh(&harness.state.pool.get().unwrap())
+        .unwrap()
+        .expect("Should record a refresh");
+    assert_eq!(refresh.total_added, 3);
+
+    let html = body_text(harness.get("/").await).await;
+    assert!(html.contains("Total found: 3"));
+    assert!(html.contains("Light and Shadow"));
+    assert!(html.contains("2020-03-01"));
+    assert!(html.contains(" thru 2020-04-30"));
+    assert!(html.contains("Artist Talk"));
+    assert!(html.contains("2020-03-06 23:59:00"));
+    assert!(html.contains("at C&#x2f;O Berlin"));
+    assert!(html.contains("at Berghain"));
+
+    let html = body_text(harness.post_form("/", "district=Friedrichshain").await).await;
+    assert!(html.contains("Total found: 1"));
+    assert!(html.contains("Klubnacht"));
+
+    // Tagged by source, and by keyword
+    let html = body_text(harness.post_form("/", "category=talk").await).await;
+    assert!(html.contains("Total found: 1"));
+    assert!(html.contains("Artist Talk"));
+    assert!(html.contains("photography"));
+
+    // Links resolve against the page's final URL, after the redirect
+    let mut hrefs = all_events(&harness.state.pool.get().unwrap())
+        .unwrap()
+        .into_iter()
+        .map(|e| e.href)
+        .collect::<Vec<String>>();
+    hrefs.sort();
+    assert_eq!(
+        hrefs,
+        vec![
+            format!("http://{}/en/artist-talk", addr),
+            format!("http://{}/en/event/1001", addr),
+            "https://www.co-berlin.org/en/light-and-shadow".to_string(),
+        ]
+    );
+
+    // Too soon to refresh again
+    let response = harness.request(Request::post("/refresh").body(Body::empty()).unwrap());
+    assert_eq!(response.await.status(), StatusCode::OK);
+    let again = latest_refresh(&harness.state.pool.get().unwrap())
+        .unwrap()
+        .unwrap();
+    assert_eq!(again.id, refresh.id);
+}
+
+#[tokio::test]
+async fn test_health_checks() {
+    let harness = Harness::with_opt(|opt| opt.max_refresh_age = Some(3600));
+
+    let response = harness.get("/healthz").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        header_str(&response, header::CONTENT_TYPE),
+        "application/json"
+    );
+    assert_eq!(body_text(response).await, r#"{"status":"ok","checks":{}}"#);
+
+    // Never refreshed, so the data is stale
+    let response = harness.get("/readyz").await;
+    assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(header_str(&response, header::CACHE_CONTROL), "no-store");
+    let json: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+    assert_eq!(json["status"], "fail");
+    assert_eq!(json["checks"]["database"]["status"], "ok");
+    assert_eq!(json["checks"]["migrations"]["status"], "ok");
+    assert_eq!(json["checks"]["refresh"]["detail"], "never refreshed");
+
+    create_refresh(&harness.state.pool.get().unwrap(), 0, 0).unwrap();
+    let response = harness.get("/readyz").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+    assert_eq!(json["status"], "ok");
+    assert_eq!(json["checks"]["refresh"]["status"], "ok");
+
+    // Without a limit, refreshes aren't checked
+    let harness = Harness::new(&[]);
+    let response = harness.get("/readyz").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let json: serde_json::Value = serde_json::from_str(&body_text(response).await).unwrap();
+    assert!(json["checks"].get("refresh").is_none());
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let addr = mock_sources().await;
+    let harness = Harness::with_opt(|opt| {
+        for (name, path) in &[("CoBerlin", "old/coberlin"), ("Berghain", "berghain")] {
+            opt.sources.get_mut(*name).unwrap().calendar_url =
+                Some(format!("http://{}/{}", addr, path));
+        }
+        opt.sources.insert(
+            "Cinema".into(),
+            SourceConfig {
+                enabled: true,
+                kind: SourceKind::Ical,
+                calendar_url: Some(format!("http://{}/cinema.ics", addr)),
+                ..SourceConfig::default()
+            },
+        );
+    });
+    harness.get("/").await;
+    harness.get("/events/999").await;
+    let response = harness.request(Request::post("/refresh").body(Body::empty()).unwrap());
+    assert_eq!(response.await.status(), StatusCode::OK);
+
+    let response = harness.get("/metrics").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        header_str(&response, header::CONTENT_TYPE),
+        "text/plain; version=0.0.4"
+    );
+    let text = body_text(response).await;
+    let samples = text
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.rsplit_once(' '))
+        .collect::<HashMap<&str, &str>>();
+    for (name, value) in &[
+        (
+            "dalia_http_requests_total{route=\"/\",method=\"GET\",status=\"200\"}",
+            "1",
+        ),
+        (
+            "dalia_http_requests_total{route=\"/events/{id}\",method=\"GET\",status=\"404\"}",
+            "1",
+        ),
+        (
+            "dalia_http_requests_total{route=\"/refresh\",method=\"POST\",status=\"200\"}",
+            "1",
+        ),
+        (
+            "dalia_http_request_duration_seconds_count{route=\"/\",method=\"GET\",status=\"200\"}",
+            "1",
+        ),
+        (
+            "dalia_db_query_duration_seconds_count{query=\"filtered_events\"}",
+            "1",
+        ),
+        (
+            "dalia_db_query_duration_seconds_count{query=\"find_event\"}",
+            "1",
+        ),
+        ("dalia_db_pool_max_connections", "2"),
+        (
+            "dalia_scrape_duration_seconds_count{source=\"Berghain\"}",
+            "1",
+        ),
+        ("dalia_events_parsed_total{source=\"Berghain\"}", "1"),
+        ("dalia_events_inserted_total{source=\"Berghain\"}", "1"),
+        ("dalia_events_failed_total{source=\"Berghain\"}", "0"),
+        ("dalia_events_parsed_total{source=\"Cinema\"}", "1"),
+        ("dalia_events_inserted_total{source=\"Cinema\"}", "1"),
+        ("dalia_events_failed_total{source=\"Cinema\"}", "1"),
+    ] {
+        assert_eq!(samples.get(name), Some(value), "{}", name);
+    }
+    assert!(samples.contains_key("dalia_source_last_success_age_seconds{source=\"Berghain\"}"));
+    // Nothing else was requested
+    let routes = samples
+        .keys()
+        .filter(|name| name.starts_with("dalia_http_requests_total"))
+        .count();
+    assert_eq!(routes, 3);
+}
+
+#[tokio::test]
+async fn test_json_ld_source() {
+    let addr = mock_sources().await;
+    let harness = Harness::with_opt(|opt| {
+        for source in opt.sources.values_mut() {
+            source.enabled = false;
+        }
+        opt.sources.insert(
+            "Venue".into(),
+            SourceConfig {
+                enabled: true,
+                kind: SourceKind::JsonLd,
+                calendar_url: Some(format!("http://{}/venue", addr)),
+                ..SourceConfig::default()
+            },
+        );
+    });
+    let response = harness.request(Request::post("/refresh").body(Body::empty()).unwrap());
+    assert_eq!(response.await.status(), StatusCode::OK);
+    // The event's location is added as a venue, whose details are filled in by hand
+    diesel::sql_query("UPDATE venues SET district = 'Mitte' WHERE name = 'Main Hall'")
+        .execute(&*harness.state.pool.get().unwrap())
+        .unwrap();
+
+    let html = body_text(harness.post_form("/", "source-venue=on").await).await;
+    assert!(html.contains("Total found: 1"));
+    assert!(html.contains("Chamber Night"));
+    assert!(html.contains("at Main Hall"));
+    assert!(html.contains("from 12 EUR"));
+    assert!(!html.contains("Main Hall - from 12 EUR"));
+    assert!(html.contains("2020-03-10 20:00:00"));
+
+    let html = body_text(harness.post_form("/", "district=Mitte").await).await;
+    assert!(html.contains("Total found: 1"));
+    assert!(html.contains("Chamber Night"));
+}
+
+#[tokio::test]
+async fn test_duplicates_across_sources() {
+    let addr = mock_sources().await;
+    let harness = Harness::with_opt(|opt| {
+        for source in opt.sources.values_mut() {
+            source.enabled = false;
+        }
+        // Distinct URLs, so the second isn't skipped as an unchanged page
+        for name in &["Venue", "Mirror"] {
+            opt.sources.insert(
+                name.to_string(),
+                SourceConfig {
+                    enabled: true,
+                    kind: SourceKind::JsonLd,
+                    calendar_url: Some(format!("http://{}/venue?{}", addr, name)),
+                    ..SourceConfig::default()
+                },
+            );
+        }
+    });
+    let response = harness.request(Request::post("/refresh").body(Body::empty()).unwrap());
+    assert_eq!(response.await.status(), StatusCode::OK);
+
+    // Whichever source was stored first is canonical, and links to the other
+    let html = body_text(
+        harness
+            .post_form("/", "source-venue=on&source-mirror=on")
+            .await,
+    )
+    .await;
+    assert!(html.contains("Total found: 1"));
+    assert!(html.contains("Also listed on"));
+
+    // Either source alone still lists it, even the duplicate
+    let mut linked = 0;
+    for form in &["source-venue=on", "source-mirror=on"] {
+        let html = body_text(harness.post_form("/", form).await).await;
+        assert!(html.contains("Total found: 1"));
+        if html.contains("Also listed on") {
+            linked += 1;
+        }
+    }
+    assert_eq!(linked, 1);
+}
+
+#[tokio::test]
+async fn test_ical_source() {
+    let addr = mock_sources().await;
+    let harness = Harness::with_opt(|opt| {
+        for source in opt.sources.values_mut() {
+            source.enabled = false;
+        }
+        opt.sources.insert(
+            "Cinema".into(),
+            SourceConfig {
+                enabled: true,
+                kind: SourceKind::Ical,
+                calendar_url: Some(format!("http://{}/cinema.ics", addr)),
+                ..SourceConfig::default()
+            },
+        );
+    });
+    let response = harness.request(Request::post("/refresh").body(Body::empty()).unwrap());
+    assert_eq!(response.await.status(), StatusCode::OK);
+
+    let html = body_text(harness.post_form("/", "source-cinema=on").await).await;
+    assert!(html.contains("Total found: 1"));
+    assert!(html.contains("Silent Film with Live Piano"));
+    assert!(html.contains("Nosferatu, accompanied live."));
+    assert!(html.contains(&format!(
+        "http:&#x2f;&#x2f;{}&#x2f;screenings&#x2f;42",
+        addr
+    )));
+}
+
+#[tokio::test]
+async fn test_refresh_skips_unchanged_pages() {
+    let addr = mock_sources().await;
+    let harness = Harness::with_opt(|opt| {
+        opt.refresh_interval = 0;
+        for name in &["CoBerlin", "Berghain"] {
+            let url = format!("http://{}/{}", addr, name.to_lowercase());
+            opt.sources.get_mut(*name).unwrap().calendar_url = Some(url);
+        }
+    });
+    for _ in 0..2 {
+        let response = harness.request(Request::post("/refresh").body(Body::empty()).unwrap());
+        assert_eq!(response.await.status(), StatusCode::OK);
+    }
+
+    // CoBerlin answers 304 to its ETag, Berghain sends the same page again
+    let refresh = latest_refresh(&harness.state.pool.get().unwrap())
+        .unwrap()
+        .unwrap();
+    assert_eq!(refresh.total_added, 0);
+    assert_eq!(refresh.sources_unchanged, 2);
+    let page = source_page(
+        &harness.state.pool.get().unwrap(),
+        &format!("http://{}/coberlin", addr),
+    )
+    .unwrap()
+    .unwrap();
+    assert_eq!(page.etag.as_deref(), Some("\"v1\""));
+}
+
+#[tokio::test]
+async fn test_refresh_from_archive() {
+    let addr = mock_sources().await;
+    let archive = tempfile::tempdir().unwrap();
+    let archive_dir = archive.path().to_path_buf();
+    let online = Harness::with_opt(|opt| {
+        opt.archive_dir = Some(archive_dir.clone());
+        for name in &["CoBerlin", "Berghain"] {
+            let url = format!("http://{}/{}", addr, name.to_lowercase());
+            opt.sources.get_mut(*name).unwrap().calendar_url = Some(url);
+        }
+    });
+    let response = online.request(Request::post("/refresh").body(Body::empty()).unwrap());
+    assert_eq!(response.await.status(), StatusCode::OK);
+
+    // Replaying the archive offline finds the same events
+    let offline = Harness::with_opt(|opt| opt.pages_from = Some(archive_dir));
+    let response = offline.request(Request::post("/refresh").body(Body::empty()).unwrap());
+    assert_eq!(response.await.status(), StatusCode::OK);
+    let html = body_text(offline.get("/").await).await;
+    assert!(html.contains("Total found: 3"));
+    assert!(html.contains("Artist Talk"));
+}
+
+#[tokio::test]
+async fn test_polite_fetching() {
+    let addr = mock_sources().await;
+    let harness = Harness::with_opt(|opt| {
+        opt.sources.get_mut("CoBerlin").unwrap().enabled = false;
+        opt.sources.get_mut("Berghain").unwrap().calendar_url =
+            Some(format!("http://{}/private", addr));
+    });
+    let response = harness
+        .request(Request::post("/refresh").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+
+    // The first fetch is told to wait a second, after which it succeeds
+    let harness = Harness::with_opt(|opt| {
+        opt.sources.get_mut("CoBerlin").unwrap().enabled = false;
+        opt.sources.get_mut("Berghain").unwrap().calendar_url =
+            Some(format!("http://{}/busy", addr));
+    });
+    let started = Instant::now();
+    let response = harness
+        .request(Request::post("/refresh").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(started.elapsed().as_millis() >= 1000);
+    let html = body_text(harness.get("/").await).await;
+    assert!(html.contains("Total found: 1"));
+}
+
+#[tokio::test]
+async fn test_refresh_unreachable_source() {
+    let harness = Harness::new(&[
+        ("CoBerlin", "http://127.0.0.1:9/coberlin".into()),
+        ("Berghain", "http://127.0.0.1:9/berghain".into()),
+    ]);
+    let response = harness
+        .request(Request::post("/refresh").body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    assert!(body_text(response).await.contains("Request ID"));
+}
+
+#[tokio::test]
+async fn test_assets() {
+    let harness = Harness::new(&[]);
+
+    let response = harness.get("/app.js").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(
+        header_str(&response, header::CONTENT_TYPE),
+        "application/javascript"
+    );
+    assert_eq!(header_str(&response, header::CACHE_CONTROL), "no-cache");
+    assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    let etag = header_str(&response, header::ETAG).to_string();
+    assert_eq!(
+        body_text(response).await,
+        include_str!("../src/assets/app.js")
+    );
+    let response = harness
+        .request(
+            Request::get("/app.js")
+                .header(header::ACCEPT_ENCODING, "deflate")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(header_str(&response, header::CONTENT_ENCODING), "deflate");
+    assert_eq!(header_str(&response, header::VARY), "Accept-Encoding");
+    assert_eq!(
+        body_text(response).await,
+        include_str!("../src/assets/app.js")
+    );
+
+    let response = harness.get(&asset_path("app.js")).await;
+    assert!(header_str(&response, header::CACHE_CONTROL).contains("immutable"));
+
+    let response = harness
+        .request(
+            Request::get("/app.js")
+                .header(header::IF_NONE_MATCH, &etag)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+
+    let response = harness.get("/favicon.ico").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header_str(&response, header::CONTENT_TYPE), "image/x-icon");
+}
+
+#[tokio::test]
+async fn test_admin_tags() {
+    let harness = Harness::with_opt(|opt| opt.admin_token = Some("s3cret".into()));
+    let conn = harness.state.pool.get().unwrap();
+    let event = NewEvent::new(
+        "Late Show",
+        None,
+        "#",
+        "",
+        "2020-03-01",
+        None,
+        EventSource::Berghain(true),
+    );
+    create_event(&conn, event.clone()).unwrap();
+    let id = last_insert_id(&conn).unwrap();
+    harness.state.tagger.tag(&conn, id, &event).unwrap();
+    let uri = format!("/admin/events/{}/tags", id);
+    let tags_request = |method: Method, token: &str, body: &str| {
+        Request::builder()
+            .method(method)
+            .uri(&uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", token))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    let response = harness.get(&uri).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(header_str(&response, header::WWW_AUTHENTICATE), "Bearer");
+    let response = harness
+        .request(tags_request(Method::GET, "guess", ""))
+        .await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = harness
+        .request(tags_request(Method::GET, "s3cret", ""))
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header_str(&response, header::CACHE_CONTROL), "no-store");
+    assert_eq!(body_text(response).await, r#"["club night"]"#);
+
+    // Replacing hides the source's tag and pins the new one through re-tagging
+    let body = r#"["Live Music ", "jazz"]"#;
+    let response = harness
+        .request(tags_request(Method::PUT, "s3cret", body))
+        .await;
+    assert_eq!(body_text(response).await, r#"["jazz","live music"]"#);
+    harness.state.tagger.tag(&conn, id, &event).unwrap();
+    let response = harness
+        .request(tags_request(Method::GET, "s3cret", ""))
+        .await;
+    assert_eq!(body_text(response).await, r#"["jazz","live music"]"#);
+
+    let html = body_text(harness.post_form("/", "category=jazz").await).await;
+    assert!(html.contains("Total found: 1"));
+    let html = body_text(harness.post_form("/", "category=club+night").await).await;
+    assert!(html.contains("Total found: 0"));
+
+    let response = harness
+        .request(tags_request(Method::PUT, "s3cret", "jazz"))
+        .await;
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = harness
+        .request(
+            Request::get("/admin/events/9999/tags")
+                .header(header::AUTHORIZATION, "Bearer s3cret")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // Off entirely without a token
+    let harness = Harness::new(&[]);
+    let response = harness.get(&uri).await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_admin_tags_change_listing_etag() {
+    let harness = Harness::with_opt(|opt| opt.admin_token = Some("s3cret".into()));
+    harness.seed();
+    let id = all_events(&harness.state.pool.get().unwrap())
+        .unwrap()
+        .into_iter()
+        .find(|e| e.title == "Klubnacht")
+        .unwrap()
+        .id;
+    let response = harness.get("/").await;
+    let etag = header_str(&response, header::ETAG).to_string();
+
+    let response = harness
+        .request(
+            Request::put(format!("/admin/events/{}/tags", id))
+                .header(header::AUTHORIZATION, "Bearer s3cret")
+                .body(Body::from(r#"["techno"]"#))
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // A client holding the old page must get the retagged one
+    let response = harness
+        .request(
+            Request::get("/")
+                .header(header::IF_NONE_MATCH, &etag)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_ne!(header_str(&response, header::ETAG), etag);
+    assert!(body_text(response).await.contains("techno"));
+}
+
+#[tokio::test]
+async fn test_not_found_and_wrong_method() {
+    let harness = Harness::new(&[]);
+
+    let response = harness.get("/no/such/page").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    assert!(body_text(response).await.contains("NOT FOUND"));
+
+    let response = harness.get("/images/../Cargo.toml").await;
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    let response = harness
+        .request(
+            Request::builder()
+                .method(Method::DELETE)
+                .uri("/")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(header_str(&response, header::ALLOW), "GET, POST");
+
+    let response = harness.get("/refresh").await;
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(header_str(&response, header::ALLOW), "POST");
+
+    // Dotted paths are only assets if there's such an asset
+    let response = harness.request(Request::post("/app.js").body(Body::empty()).unwrap());
+    assert_eq!(response.await.status(), StatusCode::METHOD_NOT_ALLOWED);
+    let response = harness.request(Request::post("/nothing.js").body(Body::empty()).unwrap());
+    assert_eq!(response.await.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        harness.get("/images/nothing.png").await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    // Every response carries its own request ID
+    let first = harness.get("/").await;
+    let second = harness.get("/no/such/page").await;
+    let id = header_str(&first, "x-request-id".parse().unwrap());
+    assert_eq!(id.len(), 36);
+    assert_ne!(id, header_str(&second, "x-request-id".parse().unwrap()));
+
+    // Error pages are compressed like any other page, for clients that accept it
+    let response = harness
+        .request(
+            Request::get("/no/such/page")
+                .header(header::ACCEPT_ENCODING, "gzip, deflate")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+    assert_eq!(header_str(&response, header::CONTENT_ENCODING), "deflate");
+    assert_eq!(header_str(&response, header::VARY), "Accept-Encoding");
+    // A zero q-value refuses a coding, and an explicit entry beats the wildcard
+    for (accept, deflated) in &[
+        ("gzip, deflate;q=0", false),
+        ("*;q=0", false),
+        ("deflate;q=0, *", false),
+        ("gzip;q=1.0, deflate;q=0.5", true),
+        ("gzip, *", true),
+        ("deflate, *;q=0", true),
+    ] {
+        let response = harness
+            .request(
+                Request::get("/no/such/page")
+                    .header(header::ACCEPT_ENCODING, *accept)
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await;
+        let encoding = response.headers().get(header::CONTENT_ENCODING);
+        assert_eq!(encoding.is_some(), *deflated, "{}", accept);
+    }
+    let response = harness.get("/no/such/page").await;
+    assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(header_str(&response, header::VARY), "Accept-Encoding");
+}